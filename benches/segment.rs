@@ -1 +1,68 @@
 extern crate segment;
+
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use segment::{bits, cache, SharedIndex, Store};
+
+const THREADS: u32 = 8;
+const OPS: u32 = 100_000;
+const KEYS: u32 = 1024;
+
+/// Runs `op` from `THREADS` threads at once and prints the mean time per call.
+fn contend<F>(name: &str, index: &SharedIndex, op: F)
+where
+    F: Fn(&SharedIndex, u32) + Send + Sync + 'static,
+{
+    let op = Arc::new(op);
+    let start = Instant::now();
+    let workers = (0..THREADS)
+        .map(|n| {
+            let index = index.clone();
+            let op = Arc::clone(&op);
+            thread::spawn(move || {
+                for i in 0..OPS {
+                    op(&index, n.wrapping_mul(7919).wrapping_add(i));
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    let elapsed = start.elapsed();
+    let nanos = elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
+    println!(
+        "{:<24} {:>8} ns/op",
+        name,
+        nanos / u64::from(THREADS * OPS)
+    );
+}
+
+fn get(index: &SharedIndex, i: u32) {
+    index.get(format!("{}", i % KEYS)).unwrap();
+}
+
+fn insert(index: &SharedIndex, i: u32) {
+    index.insert(format!("{}", i % KEYS), i).unwrap();
+}
+
+fn main() {
+    let path = "./bench_segment";
+    {
+        let store = Arc::new(Store::open(path).unwrap());
+        for k in 0..KEYS {
+            let set = (0..64).map(|id| id * KEYS + k).collect::<bits::Set>();
+            store.put(format!("{}", k), &set).unwrap();
+        }
+
+        let cap = KEYS as usize;
+        for &shards in &[1, 4, 16, 64] {
+            let index = SharedIndex::with_cache(store.clone(), cache::Shared::with_shards(shards, cap));
+            contend(&format!("get/shards={}", shards), &index, get);
+            contend(&format!("insert/shards={}", shards), &index, insert);
+        }
+    }
+    let _ = fs::remove_dir_all(path);
+}
@@ -0,0 +1,326 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use compacts::bits;
+use rocksdb;
+use super::{Bytes, Store};
+use super::store::{card_key, cardinality, check_key, encode, error_other, path_str, META};
+
+/// Builds a fresh index offline from an unsorted stream of `(key, id)` pairs.
+///
+/// Pairs are buffered up to the memory limit, sorted and spilled to run files
+/// in the temporary directory. The runs are then merged into one `bits::Set`
+/// per key, written to SST files with their cardinalities, and ingested into the `Store`.
+pub struct Builder<'a> {
+    dir: PathBuf,
+    mem: usize,
+    progress: Option<Box<FnMut(&Progress) + 'a>>,
+}
+
+/// A snapshot of the builder's progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub phase: Phase,
+    /// Number of pairs read from the input.
+    pub pairs: u64,
+    /// Number of sorted runs spilled to disk.
+    pub runs: usize,
+    /// Number of keys written to the SST file.
+    pub keys: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Sort,
+    Merge,
+    Ingest,
+    Done,
+}
+
+/// Approximate heap and inline size of a buffered pair, excluding the key bytes.
+const PAIR_OVERHEAD: usize = 32;
+
+/// Report merge progress every `MERGE_REPORT` keys.
+const MERGE_REPORT: u64 = 1 << 16;
+
+impl<'a> Builder<'a> {
+    /// Default memory limit for buffered pairs.
+    pub const DEFAULT_MEMORY: usize = 256 << 20;
+
+    /// Creates a builder that spills temporary files into `dir`.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Builder {
+            dir: dir.as_ref().to_path_buf(),
+            mem: Self::DEFAULT_MEMORY,
+            progress: None,
+        }
+    }
+
+    /// Sets the approximate number of bytes buffered before a run is spilled.
+    pub fn memory(mut self, bytes: usize) -> Self {
+        self.mem = bytes;
+        self
+    }
+
+    /// Sets a callback that receives progress reports.
+    pub fn progress<F>(mut self, f: F) -> Self
+    where
+        F: FnMut(&Progress) + 'a,
+    {
+        self.progress = Some(Box::new(f));
+        self
+    }
+
+    /// Consumes `pairs` and ingests the resulting sets into `store`.
+    /// Existing keys in `store` are overwritten.
+    /// Fails without ingesting anything if a key starts with the reserved prefix `\xffmeta/`.
+    pub fn build<I, K>(mut self, pairs: I, store: &Store) -> io::Result<Progress>
+    where
+        I: IntoIterator<Item = (K, u32)>,
+        K: AsRef<[u8]>,
+    {
+        fs::create_dir_all(&self.dir)?;
+
+        let mut state = Progress {
+            phase: Phase::Sort,
+            pairs: 0,
+            runs: 0,
+            keys: 0,
+        };
+        let mut runs = Vec::new();
+
+        let result = self.run(pairs, store, &mut state, &mut runs);
+        for run in &runs {
+            let _ = fs::remove_file(run);
+        }
+        result?;
+
+        state.phase = Phase::Done;
+        self.report(&state);
+        Ok(state)
+    }
+
+    fn run<I, K>(
+        &mut self,
+        pairs: I,
+        store: &Store,
+        state: &mut Progress,
+        runs: &mut Vec<PathBuf>,
+    ) -> io::Result<()>
+    where
+        I: IntoIterator<Item = (K, u32)>,
+        K: AsRef<[u8]>,
+    {
+        self.sort(pairs, state, runs)?;
+        if let Some(ssts) = self.merge(runs, state)? {
+            state.phase = Phase::Ingest;
+            self.report(state);
+            let ingested = store.ingest(&ssts[..]);
+            for sst in &ssts {
+                let _ = fs::remove_file(sst);
+            }
+            ingested?;
+        }
+        Ok(())
+    }
+
+    fn sort<I, K>(
+        &mut self,
+        pairs: I,
+        state: &mut Progress,
+        runs: &mut Vec<PathBuf>,
+    ) -> io::Result<()>
+    where
+        I: IntoIterator<Item = (K, u32)>,
+        K: AsRef<[u8]>,
+    {
+        let mut buf = Vec::new();
+        let mut used = 0;
+        for (key, id) in pairs {
+            let key = key.as_ref();
+            check_key(key)?;
+            used += key.len() + PAIR_OVERHEAD;
+            buf.push((key.to_vec(), id));
+            state.pairs += 1;
+
+            if used >= self.mem {
+                self.spill(&mut buf, state, runs)?;
+                used = 0;
+            }
+        }
+        if !buf.is_empty() {
+            self.spill(&mut buf, state, runs)?;
+        }
+        Ok(())
+    }
+
+    fn spill(
+        &mut self,
+        buf: &mut Vec<(Bytes, u32)>,
+        state: &mut Progress,
+        runs: &mut Vec<PathBuf>,
+    ) -> io::Result<()> {
+        buf.sort_unstable();
+        buf.dedup();
+
+        let path = self.dir.join(format!("run-{:06}", runs.len()));
+        runs.push(path.clone());
+        {
+            let mut w = BufWriter::new(File::create(&path)?);
+            for (key, id) in buf.drain(..) {
+                write_pair(&mut w, &key, id)?;
+            }
+            w.flush()?;
+        }
+
+        state.runs = runs.len();
+        self.report(state);
+        Ok(())
+    }
+
+    fn merge(&mut self, runs: &[PathBuf], state: &mut Progress) -> io::Result<Option<Vec<PathBuf>>> {
+        state.phase = Phase::Merge;
+        self.report(state);
+
+        let mut readers = Vec::with_capacity(runs.len());
+        let mut heap = BinaryHeap::with_capacity(runs.len());
+        for (i, run) in runs.iter().enumerate() {
+            let mut r = BufReader::new(File::open(run)?);
+            if let Some((key, id)) = read_pair(&mut r)? {
+                heap.push(Reverse((key, id, i)));
+            }
+            readers.push(r);
+        }
+        if heap.is_empty() {
+            return Ok(None);
+        }
+
+        let mut ssts = Ssts::new(&self.dir);
+
+        let mut cur: Option<(Bytes, bits::Set)> = None;
+        while let Some(Reverse((key, id, i))) = heap.pop() {
+            if let Some((next_key, next_id)) = read_pair(&mut readers[i])? {
+                heap.push(Reverse((next_key, next_id, i)));
+            }
+
+            if let Some((ref cur_key, ref mut set)) = cur {
+                if *cur_key == key {
+                    set.insert(id);
+                    continue;
+                }
+            }
+
+            let mut set = bits::Set::new();
+            set.insert(id);
+            if let Some((done_key, done_set)) = mem::replace(&mut cur, Some((key, set))) {
+                self.write_set(&mut ssts, &done_key, &done_set, state)?;
+            }
+        }
+        if let Some((done_key, done_set)) = cur {
+            self.write_set(&mut ssts, &done_key, &done_set, state)?;
+        }
+
+        ssts.finish().map(Some)
+    }
+
+    fn write_set(
+        &mut self,
+        ssts: &mut Ssts,
+        key: &[u8],
+        set: &bits::Set,
+        state: &mut Progress,
+    ) -> io::Result<()> {
+        let vec = encode(set)?;
+        let file = if key < META { Ssts::LOW } else { Ssts::HIGH };
+        ssts.put(file, key, &vec)?;
+        ssts.put(Ssts::CARDS, &card_key(key), &cardinality(set).to_le_bytes())?;
+        state.keys += 1;
+        if state.keys % MERGE_REPORT == 0 {
+            self.report(state);
+        }
+        Ok(())
+    }
+
+    fn report(&mut self, state: &Progress) {
+        if let Some(ref mut f) = self.progress {
+            f(state);
+        }
+    }
+}
+
+/// The SST files written by `Builder::merge`, each opened on its first key.
+///
+/// Reserved keys are rejected, so the cardinality keys sort after the set keys
+/// below the reserved prefix, and before the ones above it. Each range goes
+/// to its own file, so that the ingested files do not overlap.
+struct Ssts {
+    paths: [PathBuf; 3],
+    writers: [Option<rocksdb::SstFileWriter>; 3],
+}
+
+impl Ssts {
+    const LOW: usize = 0;
+    const CARDS: usize = 1;
+    const HIGH: usize = 2;
+
+    fn new(dir: &Path) -> Self {
+        Ssts {
+            paths: [
+                dir.join("sets.sst"),
+                dir.join("cards.sst"),
+                dir.join("sets-high.sst"),
+            ],
+            writers: [None, None, None],
+        }
+    }
+
+    fn put(&mut self, file: usize, key: &[u8], value: &[u8]) -> io::Result<()> {
+        if self.writers[file].is_none() {
+            self.writers[file] = Some(open_sst(&self.paths[file])?);
+        }
+        let sst = self.writers[file].as_mut().unwrap();
+        sst.put(key, value).map_err(error_other)
+    }
+
+    /// Finishes the files written to, and returns their paths.
+    fn finish(mut self) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for (path, sst) in self.paths.iter().zip(self.writers.iter_mut()) {
+            if let Some(ref mut sst) = *sst {
+                sst.finish().map_err(error_other)?;
+                paths.push(path.clone());
+            }
+        }
+        Ok(paths)
+    }
+}
+
+fn open_sst(path: &Path) -> io::Result<rocksdb::SstFileWriter> {
+    let env = rocksdb::EnvOptions::new();
+    let opts = rocksdb::ColumnFamilyOptions::new();
+    let mut sst = rocksdb::SstFileWriter::new(env, opts);
+    sst.open(path_str(path)?).map_err(error_other)?;
+    Ok(sst)
+}
+
+fn write_pair<W: Write>(w: &mut W, key: &[u8], id: u32) -> io::Result<()> {
+    w.write_all(&(key.len() as u32).to_le_bytes())?;
+    w.write_all(key)?;
+    w.write_all(&id.to_le_bytes())
+}
+
+fn read_pair<R: Read>(r: &mut R) -> io::Result<Option<(Bytes, u32)>> {
+    let mut word = [0; 4];
+    match r.read_exact(&mut word) {
+        Ok(()) => {}
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let mut key = vec![0; u32::from_le_bytes(word) as usize];
+    r.read_exact(&mut key)?;
+    r.read_exact(&mut word)?;
+    Ok(Some((key, u32::from_le_bytes(word))))
+}
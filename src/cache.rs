@@ -1,12 +1,18 @@
 use std::borrow::Borrow;
-use std::hash::{BuildHasher, Hash};
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{BuildHasher, Hash, Hasher};
 pub use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::mem;
 use std::ops;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use compacts::bits;
 use linked_hash_map::{self, LinkedHashMap};
-use parking_lot::Mutex;
+use parking_lot::{Mutex, MutexGuard};
 
 pub trait Cache<K, V> {
     /// Returns the value corresponding to the given key.
@@ -16,14 +22,125 @@ pub trait Cache<K, V> {
         Q: Hash + Eq;
 
     /// Insert a key-value pair, and return the dropped entry.
+    /// See `Raw::put` for entries evicted by weight.
     fn put(&self, k: K, v: V) -> Option<(K, V)>;
+
+    /// Insert a modified key-value pair, and return the dropped entries.
+    fn write(&self, k: K, v: V) -> Vec<Dropped<K, V>>;
+
+    /// Insert an unmodified key-value pair, and return the dropped entries.
+    fn load(&self, k: K, v: V) -> Vec<Dropped<K, V>>;
+
+    /// Removes the entry of `k`, and returns it.
+    fn remove<Q: ?Sized>(&self, k: &Q) -> Option<Dropped<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq;
+
+    /// Checks if the cache contains `k`.
+    /// This does _not_ affect the cache's LRU state.
+    fn exists<Q: ?Sized>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq;
+
+    /// Returns the number of key-value pairs in the cache.
+    fn len(&self) -> usize;
+
+    /// Returns `true` if the cache contains no key-value pairs.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the maximum number of key-value pairs the cache can hold.
+    fn capacity(&self) -> usize;
+
+    /// Sets the number of key-value pairs the cache can hold, and returns the evicted entries.
+    fn set_capacity(&self, cap: usize) -> Vec<Dropped<K, V>>;
+
+    /// Removes all key-value pairs from the cache, and returns them.
+    fn clear(&self) -> Vec<Dropped<K, V>>;
+
+    /// Returns the least recently used entry.
+    fn lru(&self) -> Option<(K, V)>;
+
+    /// Returns the key-value pairs in least-recently-used to most-recently-used order.
+    /// This does _not_ affect the cache's LRU state.
+    fn entries(&self) -> Vec<(K, V)>;
+
+    /// Returns the value of `k`, or inserts the one `f` returns as unmodified
+    /// if `f` returns one. Returns the dropped entries too.
+    ///
+    /// The lookup and the insertion are done at once, so `f` must not use the cache.
+    fn get_or_try_insert_with<Q: ?Sized, F, E>(
+        &self,
+        k: &Q,
+        f: F,
+    ) -> Result<(Option<V>, Vec<Dropped<K, V>>), E>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K>,
+        F: FnOnce() -> Result<Option<V>, E>;
+
+    /// Returns the value of `k`, or inserts the one `f` returns as unmodified.
+    /// Returns the dropped entries too. `f` must not use the cache.
+    fn get_or_insert_with<Q: ?Sized, F>(&self, k: &Q, f: F) -> (V, Vec<Dropped<K, V>>)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K>,
+        F: FnOnce() -> V,
+    {
+        match self.get_or_try_insert_with(k, || Ok::<_, ()>(Some(f()))) {
+            Ok((Some(v), dropped)) => (v, dropped),
+            _ => unreachable!("the value is always given"),
+        }
+    }
+}
+
+/// An entry dropped from a cache, or one too heavy to be cached at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dropped<K, V> {
+    pub key: K,
+    pub value: V,
+    /// `true` if the entry was modified since it was loaded or flushed.
+    pub dirty: bool,
+    pub cause: Cause,
+}
+
+/// Why an entry left a cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cause {
+    /// Evicted to fit the capacity or the maximum weight, or too heavy to be cached.
+    Capacity,
+    /// Removed by `remove`, `pop_lru` or `pop_mru`.
+    Removed,
+    /// Expired, lazily on access or insertion, or by `purge_expired`.
+    Expired,
+    /// Its value was replaced by a new one for the same key.
+    Replaced,
+    /// Removed by `clear`.
+    Cleared,
+}
+
+impl Cause {
+    /// Returns `true` if the entry was dropped by the cache itself, not by its user.
+    pub fn is_eviction(self) -> bool {
+        match self {
+            Cause::Capacity | Cause::Expired => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Single<K: Eq + Hash, V, S: BuildHasher>(RefCell<Raw<K, Rc<V>, S>>);
 
+/// A cache shared between threads.
+///
+/// Keys are hashed to one or more shards, each a `Raw` cache with its own lock,
+/// so threads working on different shards do not wait for each other.
 #[derive(Debug)]
-pub struct Shared<K: Eq + Hash, V, S: BuildHasher>(Arc<Mutex<Raw<K, Arc<V>, S>>>);
+pub struct Shared<K: Eq + Hash, V, S: BuildHasher>(Arc<Vec<Mutex<Raw<K, Arc<V>, S>>>>);
 
 impl<K, V, S> Single<K, V, S>
 where
@@ -36,7 +153,7 @@ where
 }
 impl<K, V, S> Cache<K, Rc<V>> for Single<K, V, S>
 where
-    K: Eq + Hash,
+    K: Eq + Hash + Clone,
     S: BuildHasher,
 {
     fn get<Q: ?Sized>(&self, k: &Q) -> Option<Rc<V>>
@@ -52,15 +169,84 @@ where
         let mut raw = self.0.borrow_mut();
         raw.put(k, v)
     }
+
+    fn write(&self, k: K, v: Rc<V>) -> Vec<Dropped<K, Rc<V>>> {
+        let mut raw = self.0.borrow_mut();
+        raw.write(k, v)
+    }
+
+    fn load(&self, k: K, v: Rc<V>) -> Vec<Dropped<K, Rc<V>>> {
+        let mut raw = self.0.borrow_mut();
+        raw.load(k, v)
+    }
+
+    fn remove<Q: ?Sized>(&self, k: &Q) -> Option<Dropped<K, Rc<V>>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let mut raw = self.0.borrow_mut();
+        raw.remove_entry(k)
+    }
+
+    fn exists<Q: ?Sized>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        self.0.borrow().exists(k)
+    }
+
+    fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.borrow().capacity()
+    }
+
+    fn set_capacity(&self, cap: usize) -> Vec<Dropped<K, Rc<V>>> {
+        self.0.borrow_mut().set_capacity(cap)
+    }
+
+    fn clear(&self) -> Vec<Dropped<K, Rc<V>>> {
+        self.0.borrow_mut().clear()
+    }
+
+    fn lru(&self) -> Option<(K, Rc<V>)> {
+        let raw = self.0.borrow();
+        let lru = raw.lru().map(|(k, v)| (k.clone(), Rc::clone(v)));
+        lru
+    }
+
+    fn entries(&self) -> Vec<(K, Rc<V>)> {
+        let raw = self.0.borrow();
+        let entries = raw.iter().map(|(k, v)| (k.clone(), Rc::clone(v))).collect();
+        entries
+    }
+
+    fn get_or_try_insert_with<Q: ?Sized, F, E>(
+        &self,
+        k: &Q,
+        f: F,
+    ) -> Result<(Option<Rc<V>>, Vec<Dropped<K, Rc<V>>>), E>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K>,
+        F: FnOnce() -> Result<Option<Rc<V>>, E>,
+    {
+        let mut raw = self.0.borrow_mut();
+        raw.get_or_try_load(k, f)
+    }
 }
 impl<K, V, S> Single<K, V, S>
 where
     K: Eq + Hash,
     S: BuildHasher,
 {
-    pub(crate) fn for_each<F, E>(&self, f: F) -> Result<(), E>
+    pub(crate) fn for_each<F, E>(&self, mut f: F) -> Result<(), E>
     where
-        F: Fn((&K, &Rc<V>)) -> Result<(), E>,
+        F: FnMut((&K, &Rc<V>)) -> Result<(), E>,
     {
         let raw = self.0.borrow();
         for elem in raw.iter() {
@@ -68,6 +254,58 @@ where
         }
         Ok(())
     }
+
+    pub(crate) fn flush<F, E>(&self, f: F) -> Result<(), E>
+    where
+        F: FnMut(&K, &Rc<V>) -> Result<(), E>,
+    {
+        let mut raw = self.0.borrow_mut();
+        raw.flush(f)
+    }
+
+    pub(crate) fn purge_expired(&self) -> Vec<Dropped<K, Rc<V>>>
+    where
+        K: Clone,
+    {
+        let mut raw = self.0.borrow_mut();
+        raw.purge_expired()
+    }
+
+    /// Calls `f` on every entry leaving the cache, see `Raw::set_listener`.
+    pub fn set_listener<F>(&self, f: F)
+    where
+        F: Fn(&Dropped<K, Rc<V>>) + Send + Sync + 'static,
+    {
+        self.0.borrow_mut().set_listener(f)
+    }
+
+    /// See `Raw::pin`.
+    pub fn pin<Q: ?Sized>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        self.0.borrow_mut().pin(k)
+    }
+
+    /// See `Raw::unpin`.
+    pub fn unpin<Q: ?Sized>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        self.0.borrow_mut().unpin(k)
+    }
+
+    /// Returns a snapshot of the counters.
+    pub fn stats(&self) -> Stats {
+        self.0.borrow().stats()
+    }
+
+    /// Resets the counters to zero.
+    pub fn reset_stats(&self) {
+        self.0.borrow_mut().reset_stats()
+    }
 }
 
 impl<K, V, S> Shared<K, V, S>
@@ -75,186 +313,1172 @@ where
     K: Eq + Hash,
     S: BuildHasher,
 {
+    /// Creates a cache of one shard.
     pub fn new(raw: Raw<K, Arc<V>, S>) -> Self {
-        Shared(Arc::new(Mutex::new(raw)))
+        Self::sharded(vec![raw])
     }
-}
-impl<K, V, S> Cache<K, Arc<V>> for Shared<K, V, S>
-where
-    K: Eq + Hash,
-    S: BuildHasher,
-{
-    fn get<Q: ?Sized>(&self, k: &Q) -> Option<Arc<V>>
+
+    /// Creates a cache of the given shards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `raws` is empty.
+    pub fn sharded(raws: Vec<Raw<K, Arc<V>, S>>) -> Self {
+        assert!(!raws.is_empty(), "a cache needs at least one shard");
+        Shared(Arc::new(raws.into_iter().map(Mutex::new).collect()))
+    }
+
+    /// Returns the number of shards.
+    pub fn shards(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Calls `f` on every entry leaving any shard, see `Raw::set_listener`.
+    /// `f` is called while the shard is locked, so it must not use this cache.
+    pub fn set_listener<F>(&self, f: F)
+    where
+        F: Fn(&Dropped<K, Arc<V>>) + Send + Sync + 'static,
+    {
+        let listener = Listener(Arc::new(f));
+        for shard in self.0.iter() {
+            shard.lock().listener = Some(listener.clone());
+        }
+    }
+
+    /// Like `set_listener`, but kept when the user sets a listener.
+    pub(crate) fn set_observer<F>(&self, f: F)
+    where
+        F: Fn(&Dropped<K, Arc<V>>) + Send + Sync + 'static,
+    {
+        let observer = Listener(Arc::new(f));
+        for shard in self.0.iter() {
+            shard.lock().observer = Some(observer.clone());
+        }
+    }
+
+    /// See `Raw::pin`.
+    pub fn pin<Q: ?Sized>(&self, k: &Q) -> bool
     where
         K: Borrow<Q>,
         Q: Hash + Eq,
     {
-        let mut raw = self.0.lock();
-        raw.get(k).map(|ptr| Arc::clone(ptr))
+        self.shard(k).pin(k)
     }
 
-    fn put(&self, k: K, v: Arc<V>) -> Option<(K, Arc<V>)> {
-        let mut raw = self.0.lock();
-        raw.put(k, v)
+    /// See `Raw::unpin`.
+    pub fn unpin<Q: ?Sized>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        self.shard(k).unpin(k)
     }
-}
-impl<K, V, S> Shared<K, V, S>
-where
-    K: Eq + Hash,
-    S: BuildHasher,
-{
-    pub(crate) fn for_each<F, E>(&self, f: F) -> Result<(), E>
+
+    /// Returns a snapshot of the counters summed over the shards.
+    pub fn stats(&self) -> Stats {
+        self.0
+            .iter()
+            .fold(Stats::default(), |sum, shard| sum + shard.lock().stats())
+    }
+
+    /// Resets the counters of every shard to zero.
+    pub fn reset_stats(&self) {
+        for shard in self.0.iter() {
+            shard.lock().reset_stats();
+        }
+    }
+
+    fn shard<Q: ?Sized>(&self, k: &Q) -> MutexGuard<Raw<K, Arc<V>, S>>
     where
-        F: Fn((&K, &Arc<V>)) -> Result<(), E>,
+        K: Borrow<Q>,
+        Q: Hash + Eq,
     {
-        let raw = self.0.lock();
-        for elem in raw.iter() {
-            f(elem)?;
+        if self.0.len() == 1 {
+            return self.0[0].lock();
         }
-        Ok(())
+        let mut hasher = DefaultHasher::new();
+        k.hash(&mut hasher);
+        self.0[hasher.finish() as usize % self.0.len()].lock()
     }
 }
+/// Splits `cap` between `n` shards, the first `cap % n` ones holding one more item.
+fn shares(n: usize, cap: usize) -> Vec<usize> {
+    assert!(n > 0, "a cache needs at least one shard");
+    (0..n)
+        .map(|i| (cap / n + (i < cap % n) as usize).max(1))
+        .collect()
+}
 
-/// A raw LRU cache.
-#[derive(Debug, Clone)]
-pub struct Raw<K, V, S = RandomState>
+impl<K, V> Shared<K, V, RandomState>
 where
     K: Eq + Hash,
-    S: BuildHasher,
 {
-    map: LinkedHashMap<K, V, S>,
-    cap: usize,
+    /// Creates a cache of `n` shards sharing `cap` evenly.
+    /// Each shard holds at least one item, so the capacity is at least `n`.
+    pub fn with_shards(n: usize, cap: usize) -> Self {
+        Self::sharded(shares(n, cap).into_iter().map(Raw::new).collect())
+    }
 }
-
-impl<K, V> Raw<K, V>
+/// Clones share the underlying cache.
+impl<K, V, S> Clone for Shared<K, V, S>
 where
     K: Eq + Hash,
+    S: BuildHasher,
 {
-    /// Creates an empty cache that can hold at most `capacity` items.
-    pub fn new(cap: usize) -> Self {
-        let map = LinkedHashMap::new();
-        Self { map, cap }
+    fn clone(&self) -> Self {
+        Shared(Arc::clone(&self.0))
     }
 }
-
-impl<K, V, S> Raw<K, V, S>
+impl<K, V, S> Cache<K, Arc<V>> for Shared<K, V, S>
 where
-    K: Eq + Hash,
+    K: Eq + Hash + Clone,
     S: BuildHasher,
 {
-    /// Creates an empty cache that can hold at most `capacity` items
-    /// with the given hash builder.
-    pub fn with_hasher(cap: usize, hash_builder: S) -> Self {
-        let map = LinkedHashMap::with_hasher(hash_builder);
-        Self { map, cap }
+    fn get<Q: ?Sized>(&self, k: &Q) -> Option<Arc<V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let mut raw = self.shard(k);
+        raw.get(k).map(|ptr| Arc::clone(ptr))
     }
 
-    /// Returns the number of key-value pairs in the cache.
-    pub fn len(&self) -> usize {
-        self.map.len()
+    fn put(&self, k: K, v: Arc<V>) -> Option<(K, Arc<V>)> {
+        let mut raw = self.shard(&k);
+        raw.put(k, v)
     }
 
-    /// Returns `true` if the cache contains no key-value pairs.
-    pub fn is_empty(&self) -> bool {
-        self.map.is_empty()
+    fn write(&self, k: K, v: Arc<V>) -> Vec<Dropped<K, Arc<V>>> {
+        let mut raw = self.shard(&k);
+        raw.write(k, v)
     }
 
-    /// Returns the maximum number of key-value pairs the cache can hold.
-    pub fn capacity(&self) -> usize {
-        self.cap
+    fn load(&self, k: K, v: Arc<V>) -> Vec<Dropped<K, Arc<V>>> {
+        let mut raw = self.shard(&k);
+        raw.load(k, v)
     }
 
-    /// Removes all key-value pairs from the cache.
-    pub fn clear(&mut self) {
-        self.map.clear();
+    fn remove<Q: ?Sized>(&self, k: &Q) -> Option<Dropped<K, Arc<V>>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let mut raw = self.shard(k);
+        raw.remove_entry(k)
     }
 
-    /// Checks if cache contains the given key.
-    /// This does _not_ affect the cache's LRU state.
-    pub fn exists<Q: ?Sized>(&self, k: &Q) -> bool
+    fn exists<Q: ?Sized>(&self, k: &Q) -> bool
     where
         K: Borrow<Q>,
         Q: Hash + Eq,
     {
-        self.map.contains_key(k)
+        self.shard(k).exists(k)
     }
 
-    /// Insert a key-value pair into cache. If the key exists, the old value is returned.
-    /// This does _not_ affect the cache's LRU state.
-    /// Use `put` to ensure that `capacity` is greater than `length`.
-    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
-        self.map.insert(k, v)
+    fn len(&self) -> usize {
+        self.0.iter().map(|shard| shard.lock().len()).sum()
     }
 
-    /// Remove a key-value pair from cache.
-    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
+    fn capacity(&self) -> usize {
+        self.0.iter().map(|shard| shard.lock().capacity()).sum()
+    }
+
+    /// Shares `cap` evenly between the shards, each holding at least one item.
+    fn set_capacity(&self, cap: usize) -> Vec<Dropped<K, Arc<V>>> {
+        let mut dropped = Vec::new();
+        for (shard, share) in self.0.iter().zip(shares(self.0.len(), cap)) {
+            dropped.extend(shard.lock().set_capacity(share));
+        }
+        dropped
+    }
+
+    fn clear(&self) -> Vec<Dropped<K, Arc<V>>> {
+        let mut dropped = Vec::new();
+        for shard in self.0.iter() {
+            dropped.extend(shard.lock().clear());
+        }
+        dropped
+    }
+
+    /// Shards keep their own order, so this is the least recently used entry
+    /// of the first shard holding any.
+    fn lru(&self) -> Option<(K, Arc<V>)> {
+        self.0
+            .iter()
+            .filter_map(|shard| {
+                let raw = shard.lock();
+                let lru = raw.lru().map(|(k, v)| (k.clone(), Arc::clone(v)));
+                lru
+            })
+            .next()
+    }
+
+    /// The entries of a sharded cache are ordered shard by shard.
+    fn entries(&self) -> Vec<(K, Arc<V>)> {
+        let mut entries = Vec::new();
+        for shard in self.0.iter() {
+            let raw = shard.lock();
+            entries.extend(raw.iter().map(|(k, v)| (k.clone(), Arc::clone(v))));
+        }
+        entries
+    }
+
+    /// `f` is called while the shard of `k` is locked.
+    fn get_or_try_insert_with<Q: ?Sized, F, E>(
+        &self,
+        k: &Q,
+        f: F,
+    ) -> Result<(Option<Arc<V>>, Vec<Dropped<K, Arc<V>>>), E>
     where
         K: Borrow<Q>,
-        Q: Hash + Eq,
+        Q: Hash + Eq + ToOwned<Owned = K>,
+        F: FnOnce() -> Result<Option<Arc<V>>, E>,
     {
-        self.map.remove(k)
+        let mut raw = self.shard(k);
+        raw.get_or_try_load(k, f)
+    }
+}
+impl<K, V, S> Shared<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    /// Raises the capacity of the shards of no capacity to one.
+    pub(crate) fn ensure_capacity(&self) {
+        for shard in self.0.iter() {
+            let mut raw = shard.lock();
+            if raw.capacity() == 0 {
+                raw.set_capacity(1);
+            }
+        }
     }
 
-    /// Sets the number of key-value pairs the cache can hold. Removes
-    /// least-recently-used key-value pairs if necessary.
-    pub fn set_capacity(&mut self, capacity: usize) {
-        for _ in capacity..self.len() {
-            self.pop_lru();
+    /// Calls `f` on every entry, locking one shard at a time.
+    pub(crate) fn for_each<F, E>(&self, mut f: F) -> Result<(), E>
+    where
+        F: FnMut((&K, &Arc<V>)) -> Result<(), E>,
+    {
+        for shard in self.0.iter() {
+            let raw = shard.lock();
+            for elem in raw.iter() {
+                f(elem)?;
+            }
         }
-        self.cap = capacity;
+        Ok(())
     }
 
-    /// Returns a mutable reference to the value corresponding to the given key,
-    /// If value is found, it is moved to the end of the cache.
-    pub fn get<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
+    /// Checks if `k` is cached with the value `v`.
+    /// This does _not_ affect the cache's LRU state.
+    pub(crate) fn is_current<Q: ?Sized>(&self, k: &Q, v: &Arc<V>) -> bool
     where
         K: Borrow<Q>,
         Q: Hash + Eq,
     {
-        self.map.get_refresh(k)
+        let raw = self.shard(k);
+        raw.map.get(k).map_or(false, |n| Arc::ptr_eq(&n.value, v))
     }
 
-    // /// Returns a mutable reference to the value corresponding to the given key,
-    // pub fn get_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
-    // where
-    //     K: Borrow<Q>,
-    //     Q: Hash + Eq,
-    // {
-    //     self.map.get_mut(k)
-    // }
+    /// Removes the expired entries of every shard, and returns them.
+    pub(crate) fn purge_expired(&self) -> Vec<Dropped<K, Arc<V>>> {
+        let mut dropped = Vec::new();
+        for shard in self.0.iter() {
+            dropped.extend(shard.lock().purge_expired());
+        }
+        dropped
+    }
 
-    /// Insert a key-value pair, and return the dropped entry.
-    pub fn put(&mut self, k: K, v: V) -> Option<(K, V)> {
-        let _swapped = self.map.insert(k, v);
-        if self.len() > self.capacity() {
-            self.pop_lru()
-        } else {
-            None
+    /// Weighs entries by their size in memory in the shards weighing nothing,
+    /// so that the weight of the dirty entries tells how much memory they take.
+    pub(crate) fn weigh_in_memory(&self)
+    where
+        V: Weigh + 'static,
+    {
+        for shard in self.0.iter() {
+            let mut raw = shard.lock();
+            if raw.weigher.is_none() {
+                // Without a weigher there is no maximum weight, so nothing is evicted.
+                raw.set_weigher(|v: &Arc<V>| v.weigh());
+            }
         }
     }
 
-    /// Return the least recently used entry.
-    #[inline]
-    pub fn lru(&self) -> Option<(&K, &V)> {
-        self.map.front()
+    /// Returns the number of dirty entries and the sum of their weights.
+    pub(crate) fn dirty_weight(&self) -> (usize, usize) {
+        self.0.iter().fold((0, 0), |(len, sum), shard| {
+            let raw = shard.lock();
+            (len + raw.dirty_len(), sum + raw.dirty_weight())
+        })
+    }
+
+    /// Returns pointers to the dirty entries, leaving them dirty.
+    pub(crate) fn dirty(&self) -> Vec<(K, Arc<V>)>
+    where
+        K: Clone,
+    {
+        let mut dirty = Vec::new();
+        for shard in self.0.iter() {
+            let raw = shard.lock();
+            dirty.extend(
+                raw.map
+                    .iter()
+                    .filter(|&(_, n)| n.dirty)
+                    .map(|(k, n)| (k.clone(), Arc::clone(&n.value))),
+            );
+        }
+        dirty
+    }
+
+    /// Marks the entry of `k` as clean if its value is still `v`.
+    pub(crate) fn clean<Q: ?Sized>(&self, k: &Q, v: &Arc<V>)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        self.shard(k).clean(k, |value| Arc::ptr_eq(value, v));
+    }
+}
+
+/// A raw cache, evicting entries as its `Policy` says.
+#[derive(Debug, Clone)]
+pub struct Raw<K, V, S = RandomState>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    map: LinkedHashMap<K, Node<V>, S>,
+    queues: Queues<K>,
+    cap: usize,
+    policy: Policy,
+    // Number of entries in the window and protected regions, and pinned.
+    window: usize,
+    protected: usize,
+    pinned: usize,
+    sketch: Option<Sketch>,
+    weigher: Option<Weigher<V>>,
+    weight: usize,
+    // Number and total weight of the dirty entries.
+    dirty_len: usize,
+    dirty_weight: usize,
+    max_weight: Option<usize>,
+    clock: Arc<Clock>,
+    after_write: Option<Duration>,
+    after_access: Option<Duration>,
+    // `true` once an entry got its own time to live.
+    timed: bool,
+    stats: Stats,
+    listener: Option<Listener<K, V>>,
+    // Told before the listener, and set by the owner of the cache rather than its user.
+    observer: Option<Listener<K, V>>,
+}
+
+/// Counters of a cache since it was created or its stats were reset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub inserts: u64,
+    /// Entries dropped by capacity, weight or expiry, including ones too heavy to be cached.
+    pub evictions: u64,
+    /// Modified entries among the dropped ones, returned to be written back.
+    pub write_backs: u64,
+    /// Total weight of the entries at the time of the snapshot.
+    pub weight: u64,
+}
+
+impl Stats {
+    /// Returns the ratio of hits to lookups, or `None` if there were no lookups.
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            None
+        } else {
+            Some(self.hits as f64 / lookups as f64)
+        }
+    }
+}
+
+impl ops::Add for Stats {
+    type Output = Stats;
+    fn add(self, that: Stats) -> Stats {
+        Stats {
+            hits: self.hits + that.hits,
+            misses: self.misses + that.misses,
+            inserts: self.inserts + that.inserts,
+            evictions: self.evictions + that.evictions,
+            write_backs: self.write_backs + that.write_backs,
+            weight: self.weight + that.weight,
+        }
+    }
+}
+
+/// A source of time for expiring entries.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that moves only when it is advanced. Clones share the time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    start: Instant,
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock {
+            start: Instant::now(),
+            elapsed: Arc::new(Mutex::new(Duration::from_secs(0))),
+        }
+    }
+
+    pub fn advance(&self, d: Duration) {
+        *self.elapsed.lock() += d;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock()
+    }
+}
+
+/// The weight of a cached value, by default its approximate size in memory in bytes.
+pub trait Weigh {
+    fn weigh(&self) -> usize;
+}
+
+impl Weigh for bits::Set {
+    fn weigh(&self) -> usize {
+        self.mem_size()
+    }
+}
+impl<T> Weigh for Vec<T> {
+    fn weigh(&self) -> usize {
+        mem::size_of::<Self>() + self.capacity() * mem::size_of::<T>()
+    }
+}
+impl<T: Weigh + ?Sized> Weigh for Rc<T> {
+    fn weigh(&self) -> usize {
+        (**self).weigh()
+    }
+}
+impl<T: Weigh + ?Sized> Weigh for Arc<T> {
+    fn weigh(&self) -> usize {
+        (**self).weigh()
+    }
+}
+
+struct Weigher<V>(Arc<Fn(&V) -> usize + Send + Sync>);
+
+struct Listener<K, V>(Arc<Fn(&Dropped<K, V>) + Send + Sync>);
+
+impl<K, V> Clone for Listener<K, V> {
+    fn clone(&self) -> Self {
+        Listener(Arc::clone(&self.0))
+    }
+}
+impl<K, V> fmt::Debug for Listener<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Listener")
+    }
+}
+
+impl<V> Clone for Weigher<V> {
+    fn clone(&self) -> Self {
+        Weigher(Arc::clone(&self.0))
+    }
+}
+impl<V> fmt::Debug for Weigher<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Weigher")
+    }
+}
+
+/// How a `Raw` cache chooses the entry to evict.
+///
+/// Entries are kept in least-recently-used to most-recently-used order
+/// whatever the policy is, except that `Clock` does not reorder entries on hits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Evicts the least recently used entry.
+    Lru,
+    /// Evicts the least frequently used entry, the least recently used among equals.
+    Lfu,
+    /// Segmented LRU. Entries start in a probationary region and are protected when hit.
+    /// At most `protected` percent of the capacity is protected; the least recently used
+    /// protected entries are moved back to probation. Evicts the least recently used
+    /// probationary entry, so that a scan does not flush the entries hit before.
+    Slru { protected: u8 },
+    /// Evicts the first entry in insertion order not hit since it was last passed over.
+    Clock,
+    /// W-TinyLFU. New entries stay in an LRU window of `window` percent of the capacity.
+    /// Entries leaving the window are admitted into an SLRU main region only if they
+    /// are estimated to be used more often than the entry they would evict.
+    TinyLfu { window: u8 },
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy::Lru
+    }
+}
+
+/// Share of the SLRU main region of `TinyLfu` that is protected, in percent.
+const TINY_LFU_PROTECTED: usize = 80;
+
+/// Returns `p` percent of `n`, saturating instead of overflowing.
+fn percent(n: usize, p: usize) -> usize {
+    (n / 100).saturating_mul(p).saturating_add(n % 100 * p / 100)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Region {
+    Window,
+    Probation,
+    Protected,
+    // Out of the policy's reach until unpinned.
+    Pinned,
+}
+
+#[derive(Debug, Clone)]
+struct Node<V> {
+    value: V,
+    dirty: bool,
+    hits: u32,
+    region: Region,
+    weight: usize,
+    // When the entry expires since written, and since last accessed.
+    expires: Option<Instant>,
+    idle: Option<Instant>,
+}
+
+impl<V> Node<V> {
+    fn new(value: V, dirty: bool) -> Self {
+        Node {
+            value,
+            dirty,
+            hits: 0,
+            region: Region::Probation,
+            weight: 0,
+            expires: None,
+            idle: None,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.region != Region::Pinned
+            && (self.expires.map_or(false, |t| t <= now) || self.idle.map_or(false, |t| t <= now))
+    }
+}
+
+/// The queue an entry waits in, see `Queues`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Region(Region),
+    // Probationary entries of `Lfu`, by number of hits.
+    Hits(u32),
+}
+
+/// Keys of the entries in least-recently-used to most-recently-used order,
+/// queued per region so that the policy finds the entry to evict without
+/// scanning the cache. `Lfu` queues probationary entries by number of hits.
+#[derive(Debug, Clone)]
+struct Queues<K: Eq + Hash> {
+    window: LinkedHashMap<K, ()>,
+    probation: LinkedHashMap<K, ()>,
+    protected: LinkedHashMap<K, ()>,
+    pinned: LinkedHashMap<K, ()>,
+    // Never holds an empty queue, so that the first one is the least frequently used.
+    hits: BTreeMap<u32, LinkedHashMap<K, ()>>,
+}
+
+impl<K: Eq + Hash> Queues<K> {
+    fn new() -> Self {
+        Queues {
+            window: LinkedHashMap::new(),
+            probation: LinkedHashMap::new(),
+            protected: LinkedHashMap::new(),
+            pinned: LinkedHashMap::new(),
+            hits: BTreeMap::new(),
+        }
+    }
+
+    fn queue(&mut self, slot: Slot) -> &mut LinkedHashMap<K, ()> {
+        match slot {
+            Slot::Region(Region::Window) => &mut self.window,
+            Slot::Region(Region::Probation) => &mut self.probation,
+            Slot::Region(Region::Protected) => &mut self.protected,
+            Slot::Region(Region::Pinned) => &mut self.pinned,
+            Slot::Hits(hits) => self.hits.entry(hits).or_insert_with(LinkedHashMap::new),
+        }
+    }
+
+    /// Returns the least recently used key of `slot`.
+    fn front(&self, slot: Slot) -> Option<&K> {
+        let queue = match slot {
+            Slot::Region(Region::Window) => &self.window,
+            Slot::Region(Region::Probation) => &self.probation,
+            Slot::Region(Region::Protected) => &self.protected,
+            Slot::Region(Region::Pinned) => &self.pinned,
+            Slot::Hits(hits) => self.hits.get(&hits)?,
+        };
+        queue.front().map(|(k, _)| k)
+    }
+
+    /// Returns the least recently used key among the least frequently used ones.
+    fn least_hit(&self) -> Option<&K> {
+        let queue = self.hits.values().next()?;
+        queue.front().map(|(k, _)| k)
+    }
+
+    fn push(&mut self, k: K, slot: Slot) {
+        self.queue(slot).insert(k, ());
+    }
+
+    fn pop_front(&mut self, slot: Slot) -> Option<K> {
+        let k = self.queue(slot).pop_front().map(|(k, _)| k);
+        self.forget(slot);
+        k
+    }
+
+    fn remove<Q: ?Sized>(&mut self, k: &Q, slot: Slot)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        self.queue(slot).remove(k);
+        self.forget(slot);
+    }
+
+    /// Moves `k` from `from` to the back of `to`.
+    fn shift<Q: ?Sized>(&mut self, k: &Q, from: Slot, to: Slot)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let key = {
+            let queue = self.queue(from);
+            if queue.get_refresh(k).is_some() && from != to {
+                // The map gives the owned key back only from its ends.
+                queue.pop_back().map(|(key, _)| key)
+            } else {
+                None
+            }
+        };
+        self.forget(from);
+        if let Some(key) = key {
+            self.push(key, to);
+        }
+    }
+
+    /// Drops the queue of `slot` if it is an empty queue of hits.
+    fn forget(&mut self, slot: Slot) {
+        if let Slot::Hits(hits) = slot {
+            if self.hits.get(&hits).map_or(false, |queue| queue.is_empty()) {
+                self.hits.remove(&hits);
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        *self = Queues::new();
+    }
+}
+
+/// A count-min sketch of 4-bit counters estimating how often keys were used.
+/// Counters are halved periodically, so that old uses are forgotten.
+#[derive(Debug, Clone)]
+struct Sketch {
+    table: Vec<u8>,
+    mask: usize,
+    additions: usize,
+    period: usize,
+}
+
+const SKETCH_ROWS: u64 = 4;
+
+/// Counters per row of a sketch at most, so that a cache limited by weight only
+/// does not allocate for its unbounded capacity.
+const SKETCH_MAX_WIDTH: usize = 1 << 18;
+
+impl Sketch {
+    fn new(cap: usize) -> Self {
+        let width = cap.max(64).min(SKETCH_MAX_WIDTH).next_power_of_two();
+        Sketch {
+            table: vec![0; width * SKETCH_ROWS as usize],
+            mask: width - 1,
+            additions: 0,
+            period: width * 10,
+        }
+    }
+
+    fn slots<Q: ?Sized + Hash>(&self, k: &Q) -> [usize; SKETCH_ROWS as usize] {
+        let mut slots = [0; SKETCH_ROWS as usize];
+        for (row, slot) in slots.iter_mut().enumerate() {
+            let mut hasher = DefaultHasher::new();
+            (row as u64).hash(&mut hasher);
+            k.hash(&mut hasher);
+            *slot = row * (self.mask + 1) + (hasher.finish() as usize & self.mask);
+        }
+        slots
+    }
+
+    fn frequency<Q: ?Sized + Hash>(&self, k: &Q) -> u8 {
+        self.slots(k).iter().map(|&i| self.table[i]).min().unwrap_or(0)
+    }
+
+    fn increment<Q: ?Sized + Hash>(&mut self, k: &Q) {
+        for i in self.slots(k).iter() {
+            if self.table[*i] < 15 {
+                self.table[*i] += 1;
+            }
+        }
+        self.additions += 1;
+        if self.additions >= self.period {
+            for c in &mut self.table {
+                *c /= 2;
+            }
+            self.additions /= 2;
+        }
+    }
+}
+
+impl<K, V> Raw<K, V>
+where
+    K: Eq + Hash,
+{
+    /// Creates an empty cache that can hold at most `capacity` items.
+    pub fn new(cap: usize) -> Self {
+        Self::with_hasher(cap, RandomState::new())
+    }
+
+    /// Creates an empty cache that can hold at most `capacity` items
+    /// and evicts them as `policy` says.
+    pub fn with_policy(cap: usize, policy: Policy) -> Self
+    where
+        K: Clone,
+    {
+        let mut raw = Self::new(cap);
+        raw.set_policy(policy);
+        raw
+    }
+}
+
+impl<K, V, S> Raw<K, V, S>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+{
+    /// Creates an empty cache that can hold at most `capacity` items
+    /// with the given hash builder.
+    pub fn with_hasher(cap: usize, hash_builder: S) -> Self {
+        let map = LinkedHashMap::with_hasher(hash_builder);
+        Self {
+            map,
+            queues: Queues::new(),
+            cap,
+            policy: Policy::Lru,
+            window: 0,
+            protected: 0,
+            pinned: 0,
+            sketch: None,
+            weigher: None,
+            weight: 0,
+            dirty_len: 0,
+            dirty_weight: 0,
+            max_weight: None,
+            clock: Arc::new(SystemClock),
+            after_write: None,
+            after_access: None,
+            timed: false,
+            stats: Stats::default(),
+            listener: None,
+            observer: None,
+        }
+    }
+
+    /// Returns the number of key-value pairs in the cache.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns `true` if the cache contains no key-value pairs.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns the maximum number of key-value pairs the cache can hold.
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// Returns the number of pinned key-value pairs.
+    pub fn pinned_len(&self) -> usize {
+        self.pinned
+    }
+
+    /// Exempts the entry of `k` from eviction and expiry until it is unpinned.
+    /// Returns `false` if there is no such entry.
+    ///
+    /// Pinned entries count toward the length and the capacity. If the cache is full
+    /// of pinned entries, entries inserted are dropped as soon as they are inserted.
+    pub fn pin<Q: ?Sized>(&mut self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let (region, hits) = match self.map.get_mut(k) {
+            Some(n) => (mem::replace(&mut n.region, Region::Pinned), n.hits),
+            None => return false,
+        };
+        match region {
+            Region::Window => self.window -= 1,
+            Region::Protected => self.protected -= 1,
+            Region::Pinned => return true,
+            Region::Probation => {}
+        }
+        let from = self.slot(region, hits);
+        self.queues.shift(k, from, Slot::Region(Region::Pinned));
+        self.pinned += 1;
+        true
+    }
+
+    /// Lets the policy evict the entry of `k` again, as if it was just inserted.
+    /// Returns `false` if there is no such pinned entry.
+    ///
+    /// The cache shrinks back to its capacity on the next insertion.
+    pub fn unpin<Q: ?Sized>(&mut self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let region = self.initial_region();
+        let hits = match self.map.get_mut(k) {
+            Some(n) => {
+                if n.region != Region::Pinned {
+                    return false;
+                }
+                n.region = region;
+                n.hits
+            }
+            None => return false,
+        };
+        let to = self.slot(region, hits);
+        self.queues.shift(k, Slot::Region(Region::Pinned), to);
+        self.pinned -= 1;
+        if region == Region::Window {
+            self.window += 1;
+        }
+        true
+    }
+
+    /// Checks if the entry of `k` is pinned.
+    pub fn is_pinned<Q: ?Sized>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        self.map.get(k).map_or(false, |n| n.region == Region::Pinned)
+    }
+
+    /// Returns the total weight of the entries.
+    /// Without a weigher, every entry weighs nothing.
+    pub fn weight(&self) -> usize {
+        self.weight
+    }
+
+    /// Returns a snapshot of the counters.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            weight: self.weight as u64,
+            ..self.stats
+        }
+    }
+
+    /// Resets the counters to zero.
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+    }
+
+    /// Returns the maximum total weight of the entries, if limited.
+    pub fn max_weight(&self) -> Option<usize> {
+        self.max_weight
+    }
+
+    /// Expires entries `ttl` after they are written, unless they have their own time to live.
+    /// Entries already cached are not affected.
+    pub fn set_expire_after_write(&mut self, ttl: Option<Duration>) {
+        self.after_write = ttl;
+    }
+
+    /// Expires entries `ttl` after they are last written or accessed.
+    /// Entries already cached are not affected until they are accessed.
+    pub fn set_expire_after_access(&mut self, ttl: Option<Duration>) {
+        self.after_access = ttl;
+    }
+
+    /// Calls `f` on every entry leaving the cache, whatever the cause,
+    /// before it is returned or dropped.
+    pub fn set_listener<F>(&mut self, f: F)
+    where
+        F: Fn(&Dropped<K, V>) + Send + Sync + 'static,
+    {
+        self.listener = Some(Listener(Arc::new(f)));
+    }
+
+    /// Sets the clock expiry is measured with. The default is `SystemClock`.
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.clock = Arc::new(clock);
+    }
+
+    /// Returns the eviction policy.
+    pub fn policy(&self) -> Policy {
+        self.policy
+    }
+
+    /// Removes all key-value pairs from the cache, and returns them
+    /// in least-recently-used to most-recently-used order.
+    pub fn clear(&mut self) -> Vec<Dropped<K, V>> {
+        let mut dropped = Vec::with_capacity(self.len());
+        while let Some((k, n)) = self.map.pop_front() {
+            dropped.push(self.dropped(k, n, Cause::Cleared));
+        }
+        self.queues.clear();
+        self.window = 0;
+        self.protected = 0;
+        self.pinned = 0;
+        self.weight = 0;
+        self.dirty_len = 0;
+        self.dirty_weight = 0;
+        dropped
+    }
+
+    /// Checks if cache contains the given key.
+    /// This does _not_ affect the cache's LRU state.
+    pub fn exists<Q: ?Sized>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        self.map.contains_key(k)
+    }
+
+    /// Remove a key-value pair from cache.
+    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        self.remove_entry(k).map(|out| out.value)
+    }
+
+    /// Remove a key-value pair from cache, and return it with whether it was modified.
+    pub fn remove_entry<Q: ?Sized>(&mut self, k: &Q) -> Option<Dropped<K, V>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let (key, n) = self.take_ref(k)?;
+        Some(self.dropped(key, n, Cause::Removed))
+    }
+
+    /// Returns a mutable reference to the value corresponding to the given key,
+    /// and records the hit as the policy says.
+    ///
+    /// Expired entries are removed, except modified ones, which are kept
+    /// until `purge_expired` or eviction returns them to be written back.
+    pub fn get<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        if !self.hit(k) {
+            return None;
+        }
+        self.map.get_mut(k).map(|n| &mut n.value)
+    }
+
+    /// Records a hit or a miss of `k` as `get` does, and returns `true` on a hit.
+    fn hit<Q: ?Sized>(&mut self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let now = self.now();
+        let (policy, after_access) = (self.policy, self.after_access);
+        // Only segmented policies protect entries hit while on probation.
+        let protects = self.protects();
+        // The entry is looked up once, to be expired or hit.
+        let found = {
+            let n = match policy {
+                // Clock does not reorder entries on hits.
+                Policy::Clock => self.map.get_mut(k),
+                _ => self.map.get_refresh(k),
+            };
+            match n {
+                Some(n) => {
+                    let expired = now.map_or(false, |now| n.is_expired(now));
+                    if expired && !n.dirty {
+                        Some(None)
+                    } else {
+                        if let (false, Some(now), Some(ttl)) = (expired, now, after_access) {
+                            n.idle = Some(now + ttl);
+                        }
+                        let (region, hits) = (n.region, n.hits);
+                        if policy == Policy::Clock {
+                            n.hits = 1;
+                        } else {
+                            n.hits = hits.saturating_add(1);
+                            if region == Region::Probation && protects {
+                                n.region = Region::Protected;
+                            }
+                        }
+                        Some(Some((region, hits)))
+                    }
+                }
+                None => None,
+            }
+        };
+        if let Some(None) = found {
+            if let Some((key, n)) = self.take_ref(k) {
+                self.dropped(key, n, Cause::Expired);
+            }
+            self.stats.misses += 1;
+            return false;
+        }
+        if let Some(ref mut sketch) = self.sketch {
+            sketch.increment(k);
+        }
+        let (region, hits) = match found {
+            Some(Some(found)) => found,
+            _ => {
+                self.stats.misses += 1;
+                return false;
+            }
+        };
+        self.stats.hits += 1;
+        if policy == Policy::Clock {
+            return true;
+        }
+        let from = self.slot(region, hits);
+        if region == Region::Probation && protects {
+            self.queues.shift(k, from, Slot::Region(Region::Protected));
+            self.protected += 1;
+            self.demote();
+        } else {
+            let to = self.slot(region, hits.saturating_add(1));
+            self.queues.shift(k, from, to);
+        }
+        true
+    }
+
+    // /// Returns a mutable reference to the value corresponding to the given key,
+    // pub fn get_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
+    // where
+    //     K: Borrow<Q>,
+    //     Q: Hash + Eq,
+    // {
+    //     self.map.get_mut(k)
+    // }
+
+    /// Checks if the entry of the given key is dirty.
+    /// This does _not_ affect the cache's LRU state.
+    pub fn is_dirty<Q: ?Sized>(&self, k: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        self.map.get(k).map_or(false, |n| n.dirty)
+    }
+
+    /// Returns the number of dirty entries.
+    pub fn dirty_len(&self) -> usize {
+        self.dirty_len
+    }
+
+    /// Returns the total weight of the dirty entries.
+    pub fn dirty_weight(&self) -> usize {
+        self.dirty_weight
+    }
+
+    /// Calls `f` on every dirty entry in least-recently-used to most-recently-used order,
+    /// and marks it as clean if `f` succeeds. Stops at the first error.
+    /// This does _not_ affect the cache's LRU state.
+    pub fn flush<F, E>(&mut self, mut f: F) -> Result<(), E>
+    where
+        F: FnMut(&K, &V) -> Result<(), E>,
+    {
+        for (k, n) in self.map.iter_mut() {
+            if n.dirty {
+                f(k, &n.value)?;
+                n.dirty = false;
+                self.dirty_len -= 1;
+                self.dirty_weight -= n.weight;
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks the entry of `k` as clean if `f` says its value was written back.
+    fn clean<Q: ?Sized, F>(&mut self, k: &Q, f: F)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+        F: FnOnce(&V) -> bool,
+    {
+        if let Some(n) = self.map.get_mut(k) {
+            if n.dirty && f(&n.value) {
+                n.dirty = false;
+                self.dirty_len -= 1;
+                self.dirty_weight -= n.weight;
+            }
+        }
+    }
+
+    /// Return the least recently used entry.
+    #[inline]
+    pub fn lru(&self) -> Option<(&K, &V)> {
+        self.map.front().map(|(k, n)| (k, &n.value))
     }
 
     /// Return the most recently used entry.
     #[inline]
     pub fn mru(&self) -> Option<(&K, &V)> {
-        self.map.back()
+        self.map.back().map(|(k, n)| (k, &n.value))
     }
 
     /// Removes and returns the least recently used entry.
     #[inline]
     pub fn pop_lru(&mut self) -> Option<(K, V)> {
-        self.map.pop_front()
+        let (k, n) = self.map.pop_front()?;
+        self.count_out(&k, &n);
+        let out = self.dropped(k, n, Cause::Removed);
+        Some((out.key, out.value))
     }
 
     /// Removes and returns the most recently used entry.
     #[inline]
     pub fn pop_mru(&mut self) -> Option<(K, V)> {
-        self.map.pop_back()
+        let (k, n) = self.map.pop_back()?;
+        self.count_out(&k, &n);
+        let out = self.dropped(k, n, Cause::Removed);
+        Some((out.key, out.value))
     }
 
     /// Returns an iterator key-value pairs
@@ -270,6 +1494,500 @@ where
     pub fn iter_mut(&mut self) -> IterMut<K, V> {
         IterMut(self.map.iter_mut())
     }
+
+    fn initial_region(&self) -> Region {
+        match self.policy {
+            Policy::TinyLfu { .. } => Region::Window,
+            _ => Region::Probation,
+        }
+    }
+
+    /// Returns the queue of an entry in `region` hit `hits` times.
+    fn slot(&self, region: Region, hits: u32) -> Slot {
+        match region {
+            Region::Probation if self.policy == Policy::Lfu => Slot::Hits(hits),
+            _ => Slot::Region(region),
+        }
+    }
+
+    /// Counts an entry already taken out of the map as dropped, and tells the listener.
+    fn dropped(&mut self, key: K, n: Node<V>, cause: Cause) -> Dropped<K, V> {
+        if cause.is_eviction() {
+            self.stats.evictions += 1;
+        }
+        // Removed and replaced values are the user's own business.
+        if n.dirty && cause != Cause::Removed && cause != Cause::Replaced {
+            self.stats.write_backs += 1;
+        }
+        let out = Dropped {
+            key,
+            value: n.value,
+            dirty: n.dirty,
+            cause,
+        };
+        if let Some(ref observer) = self.observer {
+            (observer.0)(&out);
+        }
+        if let Some(ref listener) = self.listener {
+            (listener.0)(&out);
+        }
+        out
+    }
+
+    /// Removes the entry of `k` from the map, with its key.
+    fn take_ref<Q: ?Sized>(&mut self, k: &Q) -> Option<(K, Node<V>)>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        // The map gives the owned key back only from its ends.
+        self.map.get_refresh(k)?;
+        let (key, n) = self.map.pop_back()?;
+        self.count_out(&key, &n);
+        Some((key, n))
+    }
+
+    fn now(&self) -> Option<Instant> {
+        if self.timed || self.after_write.is_some() || self.after_access.is_some() {
+            Some(self.clock.now())
+        } else {
+            None
+        }
+    }
+
+    /// Sets when `node` expires, written now with the time to live `ttl` if given.
+    fn stamp(&self, node: &mut Node<V>, ttl: Option<Duration>) {
+        if let Some(now) = self.now() {
+            node.expires = ttl.or(self.after_write).map(|d| now + d);
+            node.idle = self.after_access.map(|d| now + d);
+        }
+    }
+
+    fn weigh(&self, v: &V) -> usize {
+        self.weigher.as_ref().map_or(0, |w| (w.0)(v))
+    }
+
+    fn over_weight(&self) -> bool {
+        self.max_weight.map_or(false, |max| self.weight > max)
+    }
+
+    /// Counts `n` in, and queues its key `k`.
+    fn count_in(&mut self, k: K, n: &Node<V>) {
+        let slot = self.slot(n.region, n.hits);
+        self.queues.push(k, slot);
+        self.weight += n.weight;
+        if n.dirty {
+            self.dirty_len += 1;
+            self.dirty_weight += n.weight;
+        }
+        match n.region {
+            Region::Window => self.window += 1,
+            Region::Protected => self.protected += 1,
+            Region::Pinned => self.pinned += 1,
+            Region::Probation => {}
+        }
+    }
+
+    fn count_out<Q: ?Sized>(&mut self, k: &Q, n: &Node<V>)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let slot = self.slot(n.region, n.hits);
+        self.queues.remove(k, slot);
+        self.weight -= n.weight;
+        if n.dirty {
+            self.dirty_len -= 1;
+            self.dirty_weight -= n.weight;
+        }
+        match n.region {
+            Region::Window => self.window -= 1,
+            Region::Protected => self.protected -= 1,
+            Region::Pinned => self.pinned -= 1,
+            Region::Probation => {}
+        }
+    }
+
+    fn protects(&self) -> bool {
+        match self.policy {
+            Policy::Slru { .. } | Policy::TinyLfu { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the capacities of the window and the protected region.
+    fn regions(&self) -> (usize, usize) {
+        match self.policy {
+            Policy::Slru { protected } => (0, percent(self.cap, protected as usize)),
+            Policy::TinyLfu { window } => {
+                let window = percent(self.cap, window as usize).max(1).min(self.cap);
+                (window, percent(self.cap - window, TINY_LFU_PROTECTED))
+            }
+            _ => (0, 0),
+        }
+    }
+
+    /// Moves the least recently used protected entries back to probation.
+    fn demote(&mut self) {
+        let (_, cap) = self.regions();
+        while self.protected > cap {
+            let k = match self.queues.pop_front(Slot::Region(Region::Protected)) {
+                Some(k) => k,
+                None => break,
+            };
+            if let Some(n) = self.map.get_mut(&k) {
+                n.region = Region::Probation;
+            }
+            self.queues.push(k, Slot::Region(Region::Probation));
+            self.protected -= 1;
+        }
+    }
+}
+
+impl<K, V, S> Raw<K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    /// Sets the eviction policy. Entries already cached start over
+    /// as if they were just inserted, in their current order.
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = policy;
+        self.sketch = match policy {
+            Policy::TinyLfu { .. } => Some(Sketch::new(self.cap)),
+            _ => None,
+        };
+        let region = self.initial_region();
+        self.queues.clear();
+        for (k, n) in self.map.iter_mut() {
+            n.hits = 0;
+            if n.region != Region::Pinned {
+                n.region = region;
+            }
+            let slot = match n.region {
+                Region::Probation if policy == Policy::Lfu => Slot::Hits(0),
+                other => Slot::Region(other),
+            };
+            self.queues.push(k.clone(), slot);
+        }
+        self.window = if region == Region::Window {
+            self.map.len() - self.pinned
+        } else {
+            0
+        };
+        self.protected = 0;
+    }
+
+    /// Insert a key-value pair into cache. If the key exists, the old value is returned.
+    /// This does _not_ affect the cache's LRU state.
+    /// Use `put` to ensure that `capacity` is greater than `length`.
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        let mut node = Node::new(v, false);
+        node.region = self.initial_region();
+        node.weight = self.weigh(&node.value);
+        self.stamp(&mut node, None);
+        let old = self.take_ref(&k);
+        if old.as_ref().map_or(false, |&(_, ref old)| old.region == Region::Pinned) {
+            node.region = Region::Pinned;
+        }
+        self.count_in(k.clone(), &node);
+        self.map.insert(k, node);
+        let (key, old) = old?;
+        Some(self.dropped(key, old, Cause::Replaced).value)
+    }
+
+    /// Sets the number of key-value pairs the cache can hold.
+    /// Evicts key-value pairs as the policy says if necessary, and returns them.
+    pub fn set_capacity(&mut self, capacity: usize) -> Vec<Dropped<K, V>> {
+        self.cap = capacity;
+        if let Some(ref mut sketch) = self.sketch {
+            *sketch = Sketch::new(capacity);
+        }
+        let dropped = self.shrink();
+        self.demote();
+        dropped
+    }
+
+    /// Limits the total weight of the entries to `max`, weighing them
+    /// by their size in memory unless `set_weigher` was called.
+    /// Evicts key-value pairs as the policy says if necessary, and returns them.
+    ///
+    /// Both the capacity and the maximum weight are kept,
+    /// so pass `usize::MAX` to `new` to limit the weight only, whatever the policy.
+    pub fn set_max_weight(&mut self, max: usize) -> Vec<Dropped<K, V>>
+    where
+        V: Weigh + 'static,
+    {
+        self.max_weight = Some(max);
+        if self.weigher.is_none() {
+            self.set_weigher(|v: &V| v.weigh())
+        } else {
+            self.shrink()
+        }
+    }
+
+    /// Weighs entries with `f`. Entries already cached are weighed again,
+    /// and the ones evicted to fit the maximum weight are returned.
+    pub fn set_weigher<F>(&mut self, f: F) -> Vec<Dropped<K, V>>
+    where
+        F: Fn(&V) -> usize + Send + Sync + 'static,
+    {
+        let (mut total, mut dirty) = (0, 0);
+        for (_, n) in self.map.iter_mut() {
+            n.weight = f(&n.value);
+            total += n.weight;
+            if n.dirty {
+                dirty += n.weight;
+            }
+        }
+        self.weigher = Some(Weigher(Arc::new(f)));
+        self.weight = total;
+        self.dirty_weight = dirty;
+        self.shrink()
+    }
+
+    /// Insert a key-value pair, and return the dropped entry.
+    ///
+    /// Evicting entries to fit the maximum weight can drop more than one,
+    /// and only the first is returned. `load` returns every dropped entry.
+    pub fn put(&mut self, k: K, v: V) -> Option<(K, V)> {
+        let out = self.load(k, v).into_iter().next()?;
+        Some((out.key, out.value))
+    }
+
+    /// Insert a key-value pair marked as dirty, and return the dropped entries.
+    pub fn write(&mut self, k: K, v: V) -> Vec<Dropped<K, V>> {
+        self.push(k, Node::new(v, true), None)
+    }
+
+    /// Insert a key-value pair marked as clean, and return the dropped entries.
+    pub fn load(&mut self, k: K, v: V) -> Vec<Dropped<K, V>> {
+        self.push(k, Node::new(v, false), None)
+    }
+
+    /// Same as `write`, but the entry expires `ttl` after now.
+    pub fn write_with_ttl(&mut self, k: K, v: V, ttl: Duration) -> Vec<Dropped<K, V>> {
+        self.timed = true;
+        self.push(k, Node::new(v, true), Some(ttl))
+    }
+
+    /// Same as `load`, but the entry expires `ttl` after now.
+    pub fn load_with_ttl(&mut self, k: K, v: V, ttl: Duration) -> Vec<Dropped<K, V>> {
+        self.timed = true;
+        self.push(k, Node::new(v, false), Some(ttl))
+    }
+
+    /// Returns the entry of `k` for in-place lookup and insertion,
+    /// recording a hit or a miss as `get` does.
+    pub fn entry(&mut self, k: K) -> Entry<K, V, S> {
+        if self.hit(&k) {
+            Entry::Occupied(OccupiedEntry { raw: self, key: k })
+        } else {
+            Entry::Vacant(VacantEntry { raw: self, key: k })
+        }
+    }
+
+    fn get_or_try_load<Q: ?Sized, F, E>(
+        &mut self,
+        k: &Q,
+        f: F,
+    ) -> Result<(Option<V>, Vec<Dropped<K, V>>), E>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K>,
+        V: Clone,
+        F: FnOnce() -> Result<Option<V>, E>,
+    {
+        if let Some(v) = self.get(k) {
+            return Ok((Some(v.clone()), Vec::new()));
+        }
+        let v = match f()? {
+            Some(v) => v,
+            None => return Ok((None, Vec::new())),
+        };
+        let dropped = self.place(k.to_owned(), Node::new(v.clone(), false), None, None);
+        Ok((Some(v), dropped))
+    }
+
+    /// Removes the expired entries, including modified ones, and returns them.
+    pub fn purge_expired(&mut self) -> Vec<Dropped<K, V>> {
+        let now = match self.now() {
+            Some(now) => now,
+            None => return Vec::new(),
+        };
+        let keys = self.map
+            .iter()
+            .filter(|&(_, n)| n.is_expired(now))
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+        let mut dropped = Vec::with_capacity(keys.len());
+        for k in keys {
+            if let Some((key, n)) = self.take(k) {
+                dropped.push(self.dropped(key, n, Cause::Expired));
+            }
+        }
+        dropped
+    }
+
+    fn push(&mut self, k: K, node: Node<V>, ttl: Option<Duration>) -> Vec<Dropped<K, V>> {
+        let old = self.take_ref(&k).map(|(key, old)| {
+            let (hits, region) = (old.hits, old.region);
+            self.dropped(key, old, Cause::Replaced);
+            (hits, region)
+        });
+        self.place(k, node, ttl, old)
+    }
+
+    /// Inserts `node` for `k`, which is not cached, and returns the dropped entries.
+    /// `old` is what the policy knew of the value just replaced, if any.
+    fn place(
+        &mut self,
+        k: K,
+        mut node: Node<V>,
+        ttl: Option<Duration>,
+        old: Option<(u32, Region)>,
+    ) -> Vec<Dropped<K, V>> {
+        node.weight = self.weigh(&node.value);
+        self.stamp(&mut node, ttl);
+        self.stats.inserts += 1;
+        if self.max_weight.map_or(false, |max| node.weight > max) {
+            // Caching it would evict everything else, so it bypasses the cache
+            // and replaces the cached value if any.
+            return vec![self.dropped(k, node, Cause::Capacity)];
+        }
+        match old {
+            Some((hits, region)) => {
+                // Replacing a value keeps what the policy knows of the key.
+                node.hits = hits;
+                node.region = region;
+            }
+            None => {
+                if let Some(ref mut sketch) = self.sketch {
+                    sketch.increment(&k);
+                }
+                node.region = self.initial_region();
+            }
+        }
+        self.count_in(k.clone(), &node);
+        self.map.insert(k, node);
+        if self.len() <= self.cap && !self.over_weight() {
+            return Vec::new();
+        }
+        // Expired entries go first.
+        let mut dropped = self.expire_lru();
+        dropped.extend(self.shrink());
+        dropped
+    }
+
+    /// Removes the expired entries from the least recently used end up to the first
+    /// one not expired, and returns them. Other expired entries wait to be accessed
+    /// or purged, so that an insertion does not scan the cache.
+    fn expire_lru(&mut self) -> Vec<Dropped<K, V>> {
+        let now = match self.now() {
+            Some(now) => now,
+            None => return Vec::new(),
+        };
+        let mut dropped = Vec::new();
+        while self.map.front().map_or(false, |(_, n)| n.is_expired(now)) {
+            if let Some((k, n)) = self.map.pop_front() {
+                self.count_out(&k, &n);
+                dropped.push(self.dropped(k, n, Cause::Expired));
+            }
+        }
+        dropped
+    }
+
+    /// Evicts entries until they fit the capacity and the maximum weight.
+    fn shrink(&mut self) -> Vec<Dropped<K, V>> {
+        let mut dropped = Vec::new();
+        while self.len() > self.cap || self.over_weight() {
+            match self.evict() {
+                Some((key, n)) => {
+                    let out = self.dropped(key, n, Cause::Capacity);
+                    dropped.push(out);
+                }
+                None => break,
+            }
+        }
+        dropped
+    }
+
+    /// Removes one entry as the policy says, or returns `None` if every entry is pinned.
+    fn evict(&mut self) -> Option<(K, Node<V>)> {
+        if self.pinned == self.len() {
+            return None;
+        }
+        let key = match self.policy {
+            Policy::Lfu => self.queues.least_hit().cloned(),
+            Policy::Clock => loop {
+                let k = self.first(Region::Probation)?;
+                let hit = match self.map.get_mut(&k) {
+                    Some(n) => mem::replace(&mut n.hits, 0) > 0,
+                    None => false,
+                };
+                if !hit {
+                    break Some(k);
+                }
+                // Passed over, so it goes round to the back.
+                self.map.get_refresh(&k);
+                let slot = Slot::Region(Region::Probation);
+                self.queues.shift(&k, slot, slot);
+            },
+            Policy::TinyLfu { .. } => return self.evict_tiny_lfu(),
+            // Protected entries go only once probation is empty.
+            _ => self.first(Region::Probation).or_else(|| self.first(Region::Protected)),
+        };
+        self.take(key?)
+    }
+
+    fn evict_tiny_lfu(&mut self) -> Option<(K, Node<V>)> {
+        let (window, _) = self.regions();
+        // Entries over the window's capacity compete for the main region.
+        while self.window > window {
+            let candidate = self.first(Region::Window)?;
+            if self.len() - self.window < self.cap - window {
+                self.admit(&candidate);
+                continue;
+            }
+            let victim = match self.first(Region::Probation).or_else(|| self.first(Region::Protected)) {
+                Some(victim) => victim,
+                None => return self.take(candidate),
+            };
+            let admitted = {
+                let sketch = self.sketch.as_ref().expect("TinyLfu keeps a sketch");
+                sketch.frequency(&candidate) > sketch.frequency(&victim)
+            };
+            if admitted {
+                self.admit(&candidate);
+                return self.take(victim);
+            }
+            return self.take(candidate);
+        }
+        let victim = self.first(Region::Probation)
+            .or_else(|| self.first(Region::Protected))
+            .or_else(|| self.first(Region::Window))?;
+        self.take(victim)
+    }
+
+    /// Moves `k` from the window to probation.
+    fn admit(&mut self, k: &K) {
+        if let Some(n) = self.map.get_mut(k) {
+            n.region = Region::Probation;
+        }
+        self.queues.shift(k, Slot::Region(Region::Window), Slot::Region(Region::Probation));
+        self.window -= 1;
+    }
+
+    /// Returns the least recently used key in `region`.
+    fn first(&self, region: Region) -> Option<K> {
+        self.queues.front(Slot::Region(region)).cloned()
+    }
+
+    fn take(&mut self, k: K) -> Option<(K, Node<V>)> {
+        let n = self.map.remove(&k)?;
+        self.count_out(&k, &n);
+        Some((k, n))
+    }
 }
 
 impl<'key, K, V, S, Q> ops::Index<&'key Q> for Raw<K, V, S>
@@ -282,7 +2000,7 @@ where
     /// Returns a reference to the value corresponding to the given key.
     /// This does _not_ affect the cache's LRU state.
     fn index(&self, i: &Q) -> &Self::Output {
-        self.map.get(i).expect("cache entry not found")
+        &self.map.get(i).expect("cache entry not found").value
     }
 }
 
@@ -295,23 +2013,126 @@ where
     /// Returns a mutable reference to the value corresponding to the given key.
     /// This does _not_ affect the cache's LRU state.
     fn index_mut(&mut self, i: &Q) -> &mut V {
-        self.map.get_mut(i).expect("cache entry not found")
+        &mut self.map.get_mut(i).expect("cache entry not found").value
+    }
+}
+
+/// An entry of a `Raw` cache, see `Raw::entry`.
+pub enum Entry<'a, K: 'a + Eq + Hash, V: 'a, S: 'a + BuildHasher> {
+    Occupied(OccupiedEntry<'a, K, V, S>),
+    Vacant(VacantEntry<'a, K, V, S>),
+}
+
+/// A cached entry.
+pub struct OccupiedEntry<'a, K: 'a + Eq + Hash, V: 'a, S: 'a + BuildHasher> {
+    raw: &'a mut Raw<K, V, S>,
+    key: K,
+}
+
+/// An entry not cached.
+pub struct VacantEntry<'a, K: 'a + Eq + Hash, V: 'a, S: 'a + BuildHasher> {
+    raw: &'a mut Raw<K, V, S>,
+    key: K,
+}
+
+impl<'a, K, V, S> Entry<'a, K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    pub fn key(&self) -> &K {
+        match *self {
+            Entry::Occupied(ref e) => e.key(),
+            Entry::Vacant(ref e) => e.key(),
+        }
+    }
+
+    /// Returns the cached value, or loads the one `f` returns as unmodified.
+    /// Returns `None` instead if the value was dropped right away, e.g. for being too heavy.
+    pub fn or_load_with<F>(self, f: F) -> (Option<&'a mut V>, Vec<Dropped<K, V>>)
+    where
+        F: FnOnce() -> V,
+    {
+        match self {
+            Entry::Occupied(e) => (Some(e.into_mut()), Vec::new()),
+            Entry::Vacant(e) => {
+                let VacantEntry { raw, key } = e;
+                let dropped = raw.place(key.clone(), Node::new(f(), false), None, None);
+                (raw.map.get_mut(&key).map(|n| &mut n.value), dropped)
+            }
+        }
+    }
+}
+
+impl<'a, K, V, S> OccupiedEntry<'a, K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn get(&self) -> &V {
+        &self.raw[&self.key]
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.raw[&self.key]
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        let OccupiedEntry { raw, key } = self;
+        &mut raw[&key]
+    }
+
+    /// Checks if the entry is modified since it was loaded or flushed.
+    pub fn is_dirty(&self) -> bool {
+        self.raw.is_dirty(&self.key)
+    }
+
+    pub fn remove(self) -> V {
+        self.raw.remove(&self.key).expect("cache entry not found")
+    }
+}
+
+impl<'a, K, V, S> VacantEntry<'a, K, V, S>
+where
+    K: Eq + Hash + Clone,
+    S: BuildHasher,
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Inserts `v` as unmodified, and returns the dropped entries.
+    pub fn load(self, v: V) -> Vec<Dropped<K, V>> {
+        self.raw.place(self.key, Node::new(v, false), None, None)
+    }
+
+    /// Inserts `v` as modified, and returns the dropped entries.
+    pub fn write(self, v: V) -> Vec<Dropped<K, V>> {
+        self.raw.place(self.key, Node::new(v, true), None, None)
     }
 }
 
 /// An iterator over a cache's key-value pairs
 /// in least-recently-used to most-recently-used order.
 #[derive(Clone)]
-pub struct Iter<'a, K: 'a, V: 'a>(linked_hash_map::Iter<'a, K, V>);
+pub struct Iter<'a, K: 'a, V: 'a>(linked_hash_map::Iter<'a, K, Node<V>>);
 
 /// A mutable iterator over a cache's key-value pairs
 /// in least-recently-used to most-recently-used order.
-pub struct IterMut<'a, K: 'a, V: 'a>(linked_hash_map::IterMut<'a, K, V>);
+pub struct IterMut<'a, K: 'a, V: 'a>(linked_hash_map::IterMut<'a, K, Node<V>>);
 
 /// An iterator over a cache's key-value pairs
 /// in least-recently-used to most-recently-used order.
 #[derive(Clone)]
-pub struct IntoIter<K, V>(linked_hash_map::IntoIter<K, V>);
+pub struct IntoIter<K, V>(linked_hash_map::IntoIter<K, Node<V>>);
 
 impl<'a, K, V, S> IntoIterator for &'a Raw<K, V, S>
 where
@@ -353,7 +2174,7 @@ where
 impl<'a, K, V> Iterator for Iter<'a, K, V> {
     type Item = (&'a K, &'a V);
     fn next(&mut self) -> Option<(&'a K, &'a V)> {
-        self.0.next()
+        self.0.next().map(|(k, n)| (k, &n.value))
     }
     fn size_hint(&self) -> (usize, Option<usize>) {
         self.0.size_hint()
@@ -361,7 +2182,7 @@ impl<'a, K, V> Iterator for Iter<'a, K, V> {
 }
 impl<'a, K, V> DoubleEndedIterator for Iter<'a, K, V> {
     fn next_back(&mut self) -> Option<(&'a K, &'a V)> {
-        self.0.next_back()
+        self.0.next_back().map(|(k, n)| (k, &n.value))
     }
 }
 impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {
@@ -373,7 +2194,7 @@ impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {
 impl<'a, K, V> Iterator for IterMut<'a, K, V> {
     type Item = (&'a K, &'a mut V);
     fn next(&mut self) -> Option<(&'a K, &'a mut V)> {
-        self.0.next()
+        self.0.next().map(|(k, n)| (k, &mut n.value))
     }
     fn size_hint(&self) -> (usize, Option<usize>) {
         self.0.size_hint()
@@ -381,7 +2202,7 @@ impl<'a, K, V> Iterator for IterMut<'a, K, V> {
 }
 impl<'a, K, V> DoubleEndedIterator for IterMut<'a, K, V> {
     fn next_back(&mut self) -> Option<(&'a K, &'a mut V)> {
-        self.0.next_back()
+        self.0.next_back().map(|(k, n)| (k, &mut n.value))
     }
 }
 impl<'a, K, V> ExactSizeIterator for IterMut<'a, K, V> {
@@ -393,7 +2214,7 @@ impl<'a, K, V> ExactSizeIterator for IterMut<'a, K, V> {
 impl<K, V> Iterator for IntoIter<K, V> {
     type Item = (K, V);
     fn next(&mut self) -> Option<(K, V)> {
-        self.0.next()
+        self.0.next().map(|(k, n)| (k, n.value))
     }
     fn size_hint(&self) -> (usize, Option<usize>) {
         self.0.size_hint()
@@ -401,7 +2222,7 @@ impl<K, V> Iterator for IntoIter<K, V> {
 }
 impl<K, V> DoubleEndedIterator for IntoIter<K, V> {
     fn next_back(&mut self) -> Option<(K, V)> {
-        self.0.next_back()
+        self.0.next_back().map(|(k, n)| (k, n.value))
     }
 }
 impl<K, V> ExactSizeIterator for IntoIter<K, V> {
@@ -0,0 +1,201 @@
+use std::io;
+use std::borrow::Borrow;
+use std::sync::Arc;
+use compacts::bits;
+use rocksdb::{self, Writable};
+use super::{Bytes, Store};
+use super::store::{decode, encode, error_other};
+
+/// Prefix of the keys of a dictionary, under which its updates are serialized.
+const DICT: &[u8] = b"\xffmeta/dict/";
+const FWD: &[u8] = b"\xffmeta/dict/fwd/";
+const REV: &[u8] = b"\xffmeta/dict/rev/";
+const NEXT: &[u8] = b"\xffmeta/dict/next";
+const FREE: &[u8] = b"\xffmeta/dict/free";
+
+/// A persistent mapping between external document ids and dense `u32` ids.
+///
+/// Ids are assigned sequentially. If recycling is enabled, ids passed to
+/// `recycle` are handed out again before new ones.
+/// Dictionaries on the same `Store` never hand out the same id twice.
+#[derive(Debug)]
+pub struct Dictionary<S = Arc<Store>>
+where
+    S: Borrow<Store>,
+{
+    store: S,
+    recycle: bool,
+}
+
+fn fwd_key(ext: &[u8]) -> Vec<u8> {
+    let mut key = FWD.to_vec();
+    key.extend_from_slice(ext);
+    key
+}
+
+fn rev_key(id: u32) -> Vec<u8> {
+    let mut key = REV.to_vec();
+    key.extend_from_slice(&id.to_be_bytes());
+    key
+}
+
+fn read_id(bytes: &[u8]) -> io::Result<u32> {
+    if bytes.len() != 4 {
+        let msg = format!("invalid id length: {}", bytes.len());
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    let mut word = [0; 4];
+    word.copy_from_slice(bytes);
+    Ok(u32::from_be_bytes(word))
+}
+
+impl<S> Dictionary<S>
+where
+    S: Borrow<Store>,
+{
+    /// Creates a dictionary that never reuses ids.
+    pub fn new(store: S) -> Self {
+        Dictionary {
+            store,
+            recycle: false,
+        }
+    }
+
+    /// Creates a dictionary that reuses ids passed to `recycle`.
+    pub fn with_recycling(store: S) -> Self {
+        Dictionary {
+            store,
+            recycle: true,
+        }
+    }
+
+    /// Returns the id of the external id `ext`.
+    pub fn id<T>(&self, ext: T) -> io::Result<Option<u32>>
+    where
+        T: AsRef<[u8]>,
+    {
+        match self.store.borrow().get_bytes(&fwd_key(ext.as_ref()))? {
+            Some(bytes) => read_id(&bytes).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the external id of `id`.
+    pub fn external(&self, id: u32) -> io::Result<Option<Bytes>> {
+        self.store.borrow().get_bytes(&rev_key(id))
+    }
+
+    /// Returns the id of `ext`, assigning a new one if `ext` is unknown.
+    pub fn assign<T>(&self, ext: T) -> io::Result<u32>
+    where
+        T: AsRef<[u8]>,
+    {
+        let ext = ext.as_ref();
+        let _guard = self.store.borrow().lock(DICT);
+        if let Some(id) = self.id(ext)? {
+            return Ok(id);
+        }
+
+        let batch = rocksdb::WriteBatch::new();
+        let id = match self.take_free(&batch)? {
+            Some(id) => id,
+            None => self.take_next(&batch)?,
+        };
+        batch.put(&fwd_key(ext), &id.to_be_bytes()).map_err(error_other)?;
+        batch.put(&rev_key(id), ext).map_err(error_other)?;
+        self.store.borrow().write(&batch)?;
+        Ok(id)
+    }
+
+    /// Removes the mapping of `ext`, and returns its id.
+    /// The id is not reused until it is passed to `recycle`.
+    pub fn remove<T>(&self, ext: T) -> io::Result<Option<u32>>
+    where
+        T: AsRef<[u8]>,
+    {
+        let ext = ext.as_ref();
+        let _guard = self.store.borrow().lock(DICT);
+        let id = match self.id(ext)? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        let batch = rocksdb::WriteBatch::new();
+        batch.delete(&fwd_key(ext)).map_err(error_other)?;
+        batch.delete(&rev_key(id)).map_err(error_other)?;
+        self.store.borrow().write(&batch)?;
+        Ok(Some(id))
+    }
+
+    /// Makes `ids` available for reassignment, e.g. after `Index::purge` has
+    /// removed them from every set. Does nothing unless recycling is enabled.
+    /// Ids still mapped to an external id, or never assigned, are skipped.
+    pub fn recycle(&self, ids: &bits::Set) -> io::Result<()> {
+        if !self.recycle {
+            return Ok(());
+        }
+        let _guard = self.store.borrow().lock(DICT);
+        let store = self.store.borrow();
+        let next = self.next_id()?;
+        let mut free = self.free()?;
+        for id in ids.bits().take_while(|&id| id < next) {
+            if store.get_bytes(&rev_key(id))?.is_none() {
+                free.insert(id);
+            }
+        }
+        store.put_bytes(FREE, &encode(&free)?)
+    }
+
+    /// Translates `set` to external ids in id order.
+    /// Ids without a mapping are skipped.
+    pub fn externals(&self, set: &bits::Set) -> io::Result<Vec<Bytes>> {
+        let store = self.store.borrow();
+        let mut iter = store.raw_iter();
+        let mut exts = Vec::new();
+        for id in set.bits() {
+            let key = rev_key(id);
+            if iter.seek(rocksdb::SeekKey::Key(&key)) && iter.key() == &key[..] {
+                exts.push(iter.value().to_vec());
+            }
+        }
+        Ok(exts)
+    }
+
+    fn free(&self) -> io::Result<bits::Set> {
+        match self.store.borrow().get_bytes(FREE)? {
+            Some(bytes) => decode(&bytes),
+            None => Ok(bits::Set::new()),
+        }
+    }
+
+    fn take_free(&self, batch: &rocksdb::WriteBatch) -> io::Result<Option<u32>> {
+        if !self.recycle {
+            return Ok(None);
+        }
+        let mut free = self.free()?;
+        let id = match free.bits().next() {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        free.remove(id);
+        batch.put(FREE, &encode(&free)?).map_err(error_other)?;
+        Ok(Some(id))
+    }
+
+    fn next_id(&self) -> io::Result<u32> {
+        match self.store.borrow().get_bytes(NEXT)? {
+            Some(bytes) => read_id(&bytes),
+            None => Ok(0),
+        }
+    }
+
+    fn take_next(&self, batch: &rocksdb::WriteBatch) -> io::Result<u32> {
+        let id = self.next_id()?;
+        if id == u32::max_value() {
+            let msg = "dictionary is full".to_owned();
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+        batch.put(NEXT, &(id + 1).to_be_bytes()).map_err(error_other)?;
+        Ok(id)
+    }
+}
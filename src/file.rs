@@ -0,0 +1,400 @@
+//! An immutable, self-contained segment file.
+//!
+//! Layout, with integers in little endian:
+//!
+//! ```text
+//! header  : magic "SEGM", version u32
+//! payload : serialized sets, in key order
+//! keys    : key bytes, in key order
+//! table   : per key, key offset u64, key length u32,
+//!           set offset u64, set length u32, set checksum u32
+//! footer  : key count u64, keys offset u64, table offset u64,
+//!           keys and table checksum u32, magic "SEGM"
+//! ```
+//!
+//! Checksums are CRC-32 (IEEE).
+
+use std::io::{self, BufWriter, Seek as IoSeek, SeekFrom, Write};
+use std::cmp::Ordering;
+use std::fs::File;
+use std::path::Path;
+use compacts::bits;
+use memmap::Mmap;
+use super::{Backend, Bytes, Store};
+use super::index::DELETED;
+use super::store::{decode, encode, is_meta};
+
+const MAGIC: &[u8; 4] = b"SEGM";
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = 8;
+const ENTRY_LEN: usize = 28;
+const FOOTER_LEN: usize = 32;
+
+fn error_invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Writes a segment file. Keys must be pushed in ascending order.
+pub struct Writer {
+    out: BufWriter<File>,
+    offset: u64,
+    keys: Vec<u8>,
+    table: Vec<u8>,
+    last: Option<Bytes>,
+    count: u64,
+}
+
+impl Writer {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        Ok(Writer {
+            out,
+            offset: HEADER_LEN as u64,
+            keys: Vec::new(),
+            table: Vec::new(),
+            last: None,
+            count: 0,
+        })
+    }
+
+    /// Writes every set in `store`, including the deleted documents, into `path`.
+    pub fn write_store<P: AsRef<Path>>(store: &Store, path: P) -> io::Result<()> {
+        let mut w = Writer::create(path)?;
+        let mut deleted = store.get(DELETED)?;
+        for entry in store.seek().iter_from("") {
+            let (key, set) = entry?;
+            if deleted.is_some() && DELETED < &key[..] {
+                w.push(DELETED, &deleted.take().unwrap())?;
+            }
+            w.push(&key, &set)?;
+        }
+        if let Some(deleted) = deleted {
+            w.push(DELETED, &deleted)?;
+        }
+        w.finish()
+    }
+
+    pub fn push<T: AsRef<[u8]>>(&mut self, key: T, set: &bits::Set) -> io::Result<()> {
+        let key = key.as_ref();
+        if let Some(ref last) = self.last {
+            if &last[..] >= key {
+                let msg = format!("key {:?} is not greater than {:?}", key, last);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        }
+
+        let bytes = encode(set)?;
+        self.out.write_all(&bytes)?;
+
+        self.table.extend_from_slice(&(self.keys.len() as u64).to_le_bytes());
+        self.table.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.table.extend_from_slice(&self.offset.to_le_bytes());
+        self.table.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        self.table.extend_from_slice(&crc32(&[&bytes]).to_le_bytes());
+        self.keys.extend_from_slice(key);
+
+        self.offset += bytes.len() as u64;
+        self.last = Some(key.to_vec());
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        let keys_off = self.offset;
+        let table_off = keys_off + self.keys.len() as u64;
+        self.out.write_all(&self.keys)?;
+        self.out.write_all(&self.table)?;
+
+        self.out.write_all(&self.count.to_le_bytes())?;
+        self.out.write_all(&keys_off.to_le_bytes())?;
+        self.out.write_all(&table_off.to_le_bytes())?;
+        self.out.write_all(&crc32(&[&self.keys, &self.table]).to_le_bytes())?;
+        self.out.write_all(MAGIC)?;
+
+        let file = self.out.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()
+    }
+}
+
+/// A memory-mapped, read-only segment file.
+#[derive(Debug)]
+pub struct Reader {
+    map: Mmap,
+    count: usize,
+    keys: usize,
+    table: usize,
+}
+
+/// An iterator over the entries of a `Reader` in key order.
+/// Reserved keys are skipped.
+pub struct Range<'a> {
+    reader: &'a Reader,
+    pos: usize,
+    end: Option<Bytes>,
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[at..at + 4]);
+    u32::from_le_bytes(word)
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    let mut word = [0; 8];
+    word.copy_from_slice(&bytes[at..at + 8]);
+    u64::from_le_bytes(word)
+}
+
+impl Reader {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let len = file.seek(SeekFrom::End(0))? as usize;
+        if len < HEADER_LEN + FOOTER_LEN {
+            return Err(error_invalid_data(format!("segment too short: {}", len)));
+        }
+        let map = unsafe { Mmap::map(&file)? };
+
+        let footer = &map[len - FOOTER_LEN..];
+        if &map[..4] != MAGIC || &footer[28..] != MAGIC {
+            return Err(error_invalid_data("bad segment magic".to_owned()));
+        }
+        let version = u32_at(&map, 4);
+        if version != VERSION {
+            return Err(error_invalid_data(format!("unknown segment version: {}", version)));
+        }
+
+        let count = u64_at(footer, 0) as usize;
+        let keys = u64_at(footer, 8) as usize;
+        let table = u64_at(footer, 16) as usize;
+        let end = count
+            .checked_mul(ENTRY_LEN)
+            .and_then(|n| n.checked_add(table));
+        if keys < HEADER_LEN || keys > table || end != Some(len - FOOTER_LEN) {
+            return Err(error_invalid_data("bad segment footer".to_owned()));
+        }
+        if crc32(&[&map[keys..len - FOOTER_LEN]]) != u32_at(footer, 24) {
+            return Err(error_invalid_data("segment table checksum mismatch".to_owned()));
+        }
+        // Keys are read without checks afterwards.
+        for entry in map[table..len - FOOTER_LEN].chunks(ENTRY_LEN) {
+            let off = u64_at(entry, 0) as usize;
+            let len = u32_at(entry, 8) as usize;
+            if off.checked_add(len).map_or(true, |end| end > table - keys) {
+                return Err(error_invalid_data("bad segment key".to_owned()));
+            }
+        }
+
+        Ok(Reader {
+            map,
+            count,
+            keys,
+            table,
+        })
+    }
+
+    /// Returns the number of keys.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the `i`-th key.
+    pub fn key(&self, i: usize) -> &[u8] {
+        let entry = self.entry(i);
+        let off = self.keys + u64_at(entry, 0) as usize;
+        let len = u32_at(entry, 8) as usize;
+        &self.map[off..off + len]
+    }
+
+    /// Returns the serialized set of the `i`-th key after verifying its checksum.
+    pub fn bytes(&self, i: usize) -> io::Result<&[u8]> {
+        let entry = self.entry(i);
+        let off = u64_at(entry, 12) as usize;
+        let len = u32_at(entry, 20) as usize;
+        if off < HEADER_LEN || off.checked_add(len).map_or(true, |end| end > self.keys) {
+            return Err(error_invalid_data("bad segment entry".to_owned()));
+        }
+        let bytes = &self.map[off..off + len];
+        if crc32(&[bytes]) != u32_at(entry, 24) {
+            let msg = format!("checksum mismatch of {:?}", self.key(i));
+            return Err(error_invalid_data(msg));
+        }
+        Ok(bytes)
+    }
+
+    /// Returns the set of the given key.
+    pub fn get<T: AsRef<[u8]>>(&self, key: T) -> io::Result<Option<bits::Set>> {
+        match self.search(key.as_ref()) {
+            Ok(i) => self.set(i).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Returns the first entry whose key is greater than or equal to the given key.
+    pub fn next<T: AsRef<[u8]>>(&self, key: T) -> io::Result<Option<(Bytes, bits::Set)>> {
+        let i = match self.search(key.as_ref()) {
+            Ok(i) | Err(i) => i,
+        };
+        self.entry_at(i)
+    }
+
+    /// Returns the last entry whose key is less than or equal to the given key.
+    pub fn prev<T: AsRef<[u8]>>(&self, key: T) -> io::Result<Option<(Bytes, bits::Set)>> {
+        match self.search(key.as_ref()) {
+            Ok(i) => self.entry_at(i),
+            Err(0) => Ok(None),
+            Err(i) => self.entry_at(i - 1),
+        }
+    }
+
+    /// Returns an iterator over the entries whose keys are in `[start, end)`.
+    pub fn range<T, U>(&self, start: T, end: U) -> Range
+    where
+        T: AsRef<[u8]>,
+        U: AsRef<[u8]>,
+    {
+        let mut range = self.iter_from(start);
+        range.end = Some(end.as_ref().to_vec());
+        range
+    }
+
+    /// Returns an iterator over the entries whose keys are greater than or equal to `start`.
+    pub fn iter_from<T: AsRef<[u8]>>(&self, start: T) -> Range {
+        let pos = match self.search(start.as_ref()) {
+            Ok(i) | Err(i) => i,
+        };
+        Range {
+            reader: self,
+            pos,
+            end: None,
+        }
+    }
+
+    fn entry(&self, i: usize) -> &[u8] {
+        assert!(i < self.count, "segment key out of bounds");
+        let at = self.table + i * ENTRY_LEN;
+        &self.map[at..at + ENTRY_LEN]
+    }
+
+    fn set(&self, i: usize) -> io::Result<bits::Set> {
+        decode(self.bytes(i)?)
+    }
+
+    fn entry_at(&self, i: usize) -> io::Result<Option<(Bytes, bits::Set)>> {
+        if i < self.count {
+            let set = self.set(i)?;
+            Ok(Some((self.key(i).to_vec(), set)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn search(&self, key: &[u8]) -> Result<usize, usize> {
+        let (mut lo, mut hi) = (0, self.count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.key(mid).cmp(key) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(lo)
+    }
+}
+
+impl<'a> Iterator for Range<'a> {
+    type Item = io::Result<(Bytes, bits::Set)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pos < self.reader.count {
+            let i = self.pos;
+            let key = self.reader.key(i);
+            if let Some(ref end) = self.end {
+                if key >= &end[..] {
+                    self.pos = self.reader.count;
+                    return None;
+                }
+            }
+            self.pos += 1;
+            if !is_meta(key) {
+                return Some(self.reader.set(i).map(|set| (key.to_vec(), set)));
+            }
+        }
+        None
+    }
+}
+
+/// A segment is read-only, so `put` fails.
+impl Backend for Reader {
+    fn get(&self, key: &[u8]) -> io::Result<Option<bits::Set>> {
+        Reader::get(self, key)
+    }
+
+    fn get_sized(&self, key: &[u8]) -> io::Result<Option<(bits::Set, usize)>> {
+        match self.search(key) {
+            Ok(i) => {
+                let bytes = self.bytes(i)?;
+                Ok(Some((decode(bytes)?, bytes.len())))
+            }
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn put(&self, key: &[u8], _: &bits::Set) -> io::Result<()> {
+        let msg = format!("segment is read-only: {:?}", key);
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, msg))
+    }
+
+    fn iter_from<'a>(
+        &'a self,
+        start: &[u8],
+    ) -> Box<Iterator<Item = io::Result<(Bytes, bits::Set)>> + 'a> {
+        Box::new(Reader::iter_from(self, start))
+    }
+
+    fn keys_from<'a>(&'a self, start: &[u8]) -> Box<Iterator<Item = io::Result<Bytes>> + 'a> {
+        let pos = match self.search(start) {
+            Ok(i) | Err(i) => i,
+        };
+        let keys = (pos..self.count)
+            .map(move |i| self.key(i))
+            .filter(|key| !is_meta(key))
+            .map(|key| Ok(key.to_vec()));
+        Box::new(keys)
+    }
+}
+
+/// The lookup table of the CRC-32 (IEEE) polynomial, built at compile time.
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 1 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+fn crc32(chunks: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for chunk in chunks {
+        for &b in chunk.iter() {
+            crc = CRC_TABLE[((crc ^ u32::from(b)) & 0xFF) as usize] ^ (crc >> 8);
+        }
+    }
+    !crc
+}
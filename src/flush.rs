@@ -0,0 +1,114 @@
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use parking_lot::{Condvar, Mutex};
+
+/// When a background flusher writes modified sets to the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushPolicy {
+    /// Flush at least this often.
+    pub interval: Duration,
+    /// Flush when more than this many entries are modified.
+    pub max_dirty: Option<usize>,
+    /// Flush when the modified sets take more than this many bytes in memory.
+    pub max_dirty_bytes: Option<usize>,
+    /// How often the thresholds are checked.
+    pub poll: Duration,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        FlushPolicy {
+            interval: Duration::from_secs(5),
+            max_dirty: None,
+            max_dirty_bytes: None,
+            poll: Duration::from_millis(100),
+        }
+    }
+}
+
+impl FlushPolicy {
+    fn due(&self, dirty: usize, bytes: usize) -> bool {
+        self.max_dirty.map_or(false, |max| dirty > max)
+            || self.max_dirty_bytes.map_or(false, |max| bytes > max)
+    }
+}
+
+/// A handle to a background flusher thread.
+///
+/// Dropping the handle stops the thread as `stop` does, discarding the result.
+#[derive(Debug)]
+pub struct Flusher {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Flusher {
+    /// Spawns a thread calling `flush` as `policy` says,
+    /// where `dirty` returns the number and bytes of the modified sets.
+    pub(crate) fn spawn<D, F, E>(policy: FlushPolicy, mut dirty: D, mut flush: F, mut on_error: E) -> Self
+    where
+        D: FnMut() -> (usize, usize) + Send + 'static,
+        F: FnMut() -> io::Result<()> + Send + 'static,
+        E: FnMut(io::Error) + Send + 'static,
+    {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let signal = Arc::clone(&stopped);
+        let thread = thread::spawn(move || {
+            let &(ref lock, ref cvar) = &*signal;
+            let mut last = Instant::now();
+            loop {
+                let stop = {
+                    let mut stopped = lock.lock();
+                    if !*stopped {
+                        cvar.wait_for(&mut stopped, policy.poll);
+                    }
+                    *stopped
+                };
+                let due = stop || last.elapsed() >= policy.interval || {
+                    let (len, bytes) = dirty();
+                    policy.due(len, bytes)
+                };
+                if due {
+                    if let Err(err) = flush() {
+                        on_error(err);
+                    }
+                    last = Instant::now();
+                }
+                if stop {
+                    break;
+                }
+            }
+        });
+        Flusher {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+
+    /// Stops the thread after a last flush, and waits for it to finish.
+    pub fn stop(mut self) -> thread::Result<()> {
+        self.join()
+    }
+
+    fn join(&mut self) -> thread::Result<()> {
+        match self.thread.take() {
+            Some(thread) => {
+                {
+                    let &(ref lock, ref cvar) = &*self.stopped;
+                    *lock.lock() = true;
+                    cvar.notify_one();
+                }
+                thread.join()
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
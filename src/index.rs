@@ -1,37 +1,769 @@
-use std::io;
-use std::borrow::Borrow;
-use std::hash::BuildHasher;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::fmt;
+use std::borrow::{Borrow, Cow};
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::fs::File;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 use compacts::bits;
-use super::{Bytes, Seek, Store};
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
+use super::{file, Backend, Bytes, Flusher, FlushPolicy, Seek, Store};
+use super::schema::{Document, Kind, Schema, Value};
+use super::store::{cardinality, check_key, decode, encode};
 use super::cache::{self, Cache, RandomState};
 
+/// Reserved key of the deleted-documents set.
+pub(crate) const DELETED: &[u8] = b"\xffmeta/deleted";
+
+/// Default number of absent keys remembered by an index.
+const ABSENT_CAPACITY: usize = 1024;
+
 #[derive(Debug)]
 pub struct Index<S = Rc<Store>, H = RandomState>
 where
-    S: Borrow<Store>,
+    S: Backend,
     H: BuildHasher,
 {
     store: S,
     cache: cache::Single<Bytes, bits::Set, H>,
+    // `None` until loaded, `Some(None)` if there are no tombstones.
+    deleted: RefCell<Option<Option<Rc<bits::Set>>>>,
+    // Keys known to have no set, most recently looked up last.
+    absent: RefCell<cache::Raw<Bytes, ()>>,
+    // Second tier of serialized sets evicted from the cache, if any.
+    serialized: RefCell<Option<cache::Raw<Bytes, Bytes>>>,
+    on_drop: OnDropError,
+    closed: bool,
+    counters: Counters,
 }
 
 #[derive(Debug)]
 pub struct SharedIndex<S = Arc<Store>, H = RandomState>
 where
-    S: Borrow<Store>,
+    S: Backend,
     H: BuildHasher,
 {
     store: S,
     cache: cache::Shared<Bytes, bits::Set, H>,
+    // Read on every `get`, written by deletes and purges only.
+    deleted: Arc<RwLock<Option<Option<Arc<bits::Set>>>>>,
+    absent: Arc<Mutex<cache::Raw<Bytes, ()>>>,
+    serialized: Arc<Mutex<Option<cache::Raw<Bytes, Bytes>>>>,
+    on_drop: OnDropError,
+    closed: bool,
+    // Number of live handles; the last one dropped flushes the cache.
+    handles: Arc<AtomicUsize>,
+    writes: Arc<Writes>,
+    flights: Arc<Flights>,
+    counters: Arc<Counters>,
+}
+
+/// Counters of the store accesses of an index since it was created or its stats were reset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Sets read from the store.
+    pub reads: u64,
+    /// Bytes of serialized sets decoded, read from the store or the serialized tier.
+    pub bytes_decoded: u64,
+    /// Modified sets failed to be written back.
+    pub write_back_failures: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    reads: AtomicUsize,
+    decoded: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+impl Counters {
+    /// Counts a store read, of `len` bytes if a set was found.
+    fn read(&self, len: Option<usize>) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        if let Some(len) = len {
+            self.decoded(len);
+        }
+    }
+
+    fn decoded(&self, len: usize) {
+        self.decoded.fetch_add(len, Ordering::Relaxed);
+    }
+
+    fn write_back<T>(&self, result: io::Result<T>) -> io::Result<T> {
+        if result.is_err() {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn stats(&self) -> StoreStats {
+        StoreStats {
+            reads: self.reads.load(Ordering::Relaxed) as u64,
+            bytes_decoded: self.decoded.load(Ordering::Relaxed) as u64,
+            write_back_failures: self.failures.load(Ordering::Relaxed) as u64,
+        }
+    }
+
+    fn reset(&self) {
+        self.reads.store(0, Ordering::Relaxed);
+        self.decoded.store(0, Ordering::Relaxed);
+        self.failures.store(0, Ordering::Relaxed);
+    }
+}
+
+/// What to do if writing modified sets fails when an index is dropped.
+/// The default ignores the error, which `close` returns instead.
+#[derive(Clone)]
+pub enum OnDropError {
+    /// Call the function with the error, e.g. to log it.
+    Report(Arc<Fn(&io::Error) + Send + Sync>),
+    /// Panic, unless the thread is already panicking.
+    Panic,
+}
+
+impl Default for OnDropError {
+    fn default() -> Self {
+        OnDropError::report(|_| ())
+    }
+}
+
+impl fmt::Debug for OnDropError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OnDropError::Report(_) => f.write_str("Report"),
+            OnDropError::Panic => f.write_str("Panic"),
+        }
+    }
+}
+
+impl OnDropError {
+    /// Calls `f` with the error.
+    pub fn report<F>(f: F) -> Self
+    where
+        F: Fn(&io::Error) + Send + Sync + 'static,
+    {
+        OnDropError::Report(Arc::new(f))
+    }
+
+    fn handle(&self, err: &io::Error) {
+        match *self {
+            OnDropError::Report(ref f) => f(err),
+            OnDropError::Panic => {
+                if !thread::panicking() {
+                    panic!("failed to flush index on drop: {}", err)
+                }
+            }
+        }
+    }
+}
+
+/// Orders the writes of `SharedIndex` handles, so that no handle reads
+/// or writes back a set older than one already written.
+///
+/// Locks are taken in order: a cache shard, then a stripe of `pending`.
+#[derive(Debug, Default)]
+struct Writes {
+    pending: Stripes<Pending>,
+    // Serialize the updates of a key.
+    keys: Stripes,
+    // Serialize the writes of a key to the store.
+    store: Stripes,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    // Modified sets evicted from the cache and not yet written.
+    sets: HashMap<Bytes, Arc<bits::Set>>,
+    // Number of sets of the stripe written to the store so far.
+    written: u64,
+}
+
+const STRIPES: usize = 64;
+
+fn stripe(key: &[u8]) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % STRIPES
+}
+
+#[derive(Debug)]
+struct Stripes<T = ()>(Vec<Mutex<T>>);
+
+impl<T: Default> Default for Stripes<T> {
+    fn default() -> Self {
+        Stripes((0..STRIPES).map(|_| Mutex::default()).collect())
+    }
+}
+
+impl<T> Stripes<T> {
+    fn lock(&self, key: &[u8]) -> MutexGuard<T> {
+        self.0[stripe(key)].lock()
+    }
+}
+
+/// Loads of `SharedIndex` in flight, so that threads missing the same key
+/// wait for one load instead of each reading the store.
+#[derive(Debug, Default)]
+struct Flights(Mutex<HashMap<Bytes, Arc<Flight>>>);
+
+type Loaded = Result<Option<Arc<bits::Set>>, (io::ErrorKind, String)>;
+
+#[derive(Debug, Default)]
+struct Flight {
+    // `None` until the load is done.
+    loaded: Mutex<Option<Loaded>>,
+    done: Condvar,
+}
+
+/// The thread loading a key. Dropping it releases the waiting threads,
+/// with an error if the load did not finish.
+struct Leader<'a> {
+    flights: &'a Flights,
+    key: &'a [u8],
+    flight: Arc<Flight>,
+}
+
+enum Joined<'a> {
+    Leader(Leader<'a>),
+    Loaded(io::Result<Option<Arc<bits::Set>>>),
+}
+
+impl Flights {
+    /// Returns the leader of a new load of `key`, or waits for the one in flight.
+    fn join<'a>(&'a self, key: &'a [u8]) -> Joined<'a> {
+        let flight = {
+            let mut flights = self.0.lock();
+            let in_flight = flights.get(key).cloned();
+            match in_flight {
+                Some(flight) => flight,
+                None => {
+                    let flight = Arc::new(Flight::default());
+                    flights.insert(key.to_vec(), Arc::clone(&flight));
+                    return Joined::Leader(Leader {
+                        flights: self,
+                        key,
+                        flight,
+                    });
+                }
+            }
+        };
+        let mut loaded = flight.loaded.lock();
+        while loaded.is_none() {
+            flight.done.wait(&mut loaded);
+        }
+        Joined::Loaded(match *loaded {
+            Some(Ok(ref ptr)) => Ok(ptr.clone()),
+            Some(Err((kind, ref msg))) => Err(io::Error::new(kind, msg.clone())),
+            None => unreachable!(),
+        })
+    }
+}
+
+impl<'a> Leader<'a> {
+    fn land(self, result: &io::Result<Option<Arc<bits::Set>>>) {
+        *self.flight.loaded.lock() = Some(match *result {
+            Ok(ref ptr) => Ok(ptr.clone()),
+            Err(ref err) => Err((err.kind(), err.to_string())),
+        });
+    }
+}
+
+impl<'a> Drop for Leader<'a> {
+    fn drop(&mut self) {
+        self.flights.0.lock().remove(self.key);
+        let mut loaded = self.flight.loaded.lock();
+        if loaded.is_none() {
+            *loaded = Some(Err((io::ErrorKind::Other, "the loading thread panicked".to_owned())));
+        }
+        self.flight.done.notify_all();
+    }
+}
+
+impl Writes {
+    /// Keeps `out` as pending if it is a modified set. Called by the cache
+    /// while the shard of `out` is locked, so that no reader missing the cache
+    /// misses the pending set too.
+    fn pend(&self, out: &cache::Dropped<Bytes, Arc<bits::Set>>) {
+        // A replaced set is superseded by the one replacing it.
+        if out.dirty && out.cause != cache::Cause::Replaced {
+            self.pending.lock(&out.key).sets.insert(out.key.clone(), Arc::clone(&out.value));
+        }
+    }
+
+    /// Forgets the set pending for `key`, superseded by `ptr` once cached,
+    /// unless `ptr` is the one pending, evicted since.
+    fn supersede(&self, key: &[u8], ptr: &Arc<bits::Set>) {
+        let mut pending = self.pending.lock(key);
+        if pending.sets.get(key).map_or(false, |p| !Arc::ptr_eq(p, ptr)) {
+            pending.sets.remove(key);
+        }
+    }
+
+    /// Writes `ptr` to the store, unless it has been replaced by a newer set,
+    /// which is written by its own writer.
+    fn persist<S, H>(
+        &self,
+        store: &S,
+        cache: &cache::Shared<Bytes, bits::Set, H>,
+        key: &[u8],
+        ptr: &Arc<bits::Set>,
+    ) -> io::Result<()>
+    where
+        S: Backend,
+        H: BuildHasher,
+    {
+        let _guard = self.store.lock(key);
+        let pending = self.pending.lock(key).sets.get(key).map_or(false, |p| Arc::ptr_eq(p, ptr));
+        if !pending && !cache.is_current(key, ptr) {
+            return Ok(());
+        }
+        store.put(key, ptr)?;
+        {
+            let mut pending = self.pending.lock(key);
+            pending.written += 1;
+            if pending.sets.get(key).map_or(false, |p| Arc::ptr_eq(p, ptr)) {
+                pending.sets.remove(key);
+            }
+        }
+        cache.clean(key, ptr);
+        Ok(())
+    }
+
+    /// Writes every modified set, cached or pending.
+    fn persist_all<S, H>(&self, store: &S, cache: &cache::Shared<Bytes, bits::Set, H>) -> io::Result<()>
+    where
+        S: Backend,
+        H: BuildHasher,
+    {
+        let mut sets = cache.dirty();
+        for stripe in &self.pending.0 {
+            sets.extend(stripe.lock().sets.iter().map(|(k, v)| (k.clone(), Arc::clone(v))));
+        }
+        for (key, ptr) in sets {
+            self.persist(store, cache, &key, &ptr)?;
+        }
+        Ok(())
+    }
+}
+
+/// Clones share the cache and the deleted-documents set.
+impl<S, H> Clone for SharedIndex<S, H>
+where
+    S: Backend + Clone,
+    H: BuildHasher,
+{
+    fn clone(&self) -> Self {
+        self.handles.fetch_add(1, Ordering::SeqCst);
+        SharedIndex {
+            store: self.store.clone(),
+            cache: self.cache.clone(),
+            deleted: Arc::clone(&self.deleted),
+            absent: Arc::clone(&self.absent),
+            serialized: Arc::clone(&self.serialized),
+            on_drop: self.on_drop.clone(),
+            closed: false,
+            handles: Arc::clone(&self.handles),
+            writes: Arc::clone(&self.writes),
+            flights: Arc::clone(&self.flights),
+            counters: Arc::clone(&self.counters),
+        }
+    }
+}
+
+impl<S, H> Index<S, H>
+where
+    S: Backend,
+    H: BuildHasher,
+{
+    fn from_parts(store: S, cache: cache::Single<Bytes, bits::Set, H>) -> Self {
+        Index {
+            store,
+            cache,
+            deleted: Default::default(),
+            absent: RefCell::new(cache::Raw::new(ABSENT_CAPACITY)),
+            serialized: Default::default(),
+            on_drop: OnDropError::default(),
+            closed: false,
+            counters: Counters::default(),
+        }
+    }
+
+    /// Writes the sets modified since they were loaded or last written.
+    pub fn snapshot(&self) -> io::Result<()> {
+        let flushed = self.cache.flush(|key, ptr| self.store_put(key, &*ptr));
+        self.counters.write_back(flushed)
+    }
+
+    /// Removes expired sets from the cache, writing the modified ones.
+    pub fn purge_expired(&self) -> io::Result<()> {
+        let dropped = self.cache.purge_expired();
+        self.write_back(dropped)
+    }
+
+    fn exclusive<T, F>(&self, _key: &[u8], f: F) -> io::Result<T>
+    where
+        F: FnOnce() -> io::Result<T>,
+    {
+        f()
+    }
+
+    /// Returns the set of `key`, loading it if it is not cached.
+    fn load(&self, key: &[u8]) -> io::Result<Option<Rc<bits::Set>>> {
+        let (ptr, dropped) = self.cache.get_or_try_insert_with(key, || -> io::Result<_> {
+            if self.absent.borrow_mut().get(key).is_some() {
+                return Ok(None);
+            }
+            if let Some(bytes) = self.take_serialized(key) {
+                return Ok(Some(Rc::new(self.decode(&bytes)?)));
+            }
+            let set = self.store_get(key)?;
+            if set.is_none() {
+                self.absent.borrow_mut().put(key.to_vec(), ());
+            }
+            Ok(set.map(Rc::new))
+        })?;
+        self.write_back(dropped)?;
+        Ok(ptr)
+    }
+
+    fn cache_put(&self, key: &[u8], ptr: Rc<bits::Set>) -> io::Result<()> {
+        self.absent.borrow_mut().remove(key);
+        self.forget_serialized(key);
+        let dropped = self.cache.write(key.to_vec(), ptr);
+        self.write_back(dropped)
+    }
+
+    /// Writes back the modified sets `f` drops from the cache.
+    fn drop_with<F>(&self, f: F) -> io::Result<()>
+    where
+        F: FnOnce() -> Vec<cache::Dropped<Bytes, Rc<bits::Set>>>,
+    {
+        self.write_back(f())
+    }
+
+    /// Writes the modified sets of `dropped`, all of them even if one fails,
+    /// and keeps the evicted ones written or unmodified in the serialized tier.
+    fn write_back(&self, dropped: Vec<cache::Dropped<Bytes, Rc<bits::Set>>>) -> io::Result<()> {
+        let mut result = Ok(());
+        for out in &dropped {
+            let put = if out.dirty {
+                let put = self.store_put(&out.key, &out.value);
+                self.counters.write_back(put)
+            } else {
+                Ok(())
+            };
+            if put.is_ok() {
+                self.serialize(out);
+            }
+            result = result.and(put);
+        }
+        result
+    }
+
+    /// Removes `purged` from the set of `key` read from the store.
+    fn purge_stored(&self, key: &[u8], set: &bits::Set, purged: &bits::Set) -> io::Result<()> {
+        self.forget_serialized(key);
+        self.store_put(key, &difference(set, purged))
+    }
+}
+
+impl<S, H> SharedIndex<S, H>
+where
+    S: Backend,
+    H: BuildHasher,
+{
+    fn from_parts(store: S, cache: cache::Shared<Bytes, bits::Set, H>) -> Self {
+        let writes = Arc::new(Writes::default());
+        {
+            let writes = Arc::clone(&writes);
+            cache.set_observer(move |out| writes.pend(out));
+        }
+        SharedIndex {
+            store,
+            cache,
+            deleted: Default::default(),
+            absent: Arc::new(Mutex::new(cache::Raw::new(ABSENT_CAPACITY))),
+            serialized: Default::default(),
+            on_drop: OnDropError::default(),
+            closed: false,
+            handles: Arc::new(AtomicUsize::new(1)),
+            writes,
+            flights: Arc::default(),
+            counters: Arc::default(),
+        }
+    }
+
+    /// Creates an index on a cache built by the caller, e.g. `cache::Shared::with_shards`.
+    /// Shards of no capacity hold one item, as in `new`.
+    pub fn with_cache(store: S, cache: cache::Shared<Bytes, bits::Set, H>) -> Self {
+        cache.ensure_capacity();
+        Self::from_parts(store, cache)
+    }
+
+    /// Writes the sets modified since they were loaded or last written.
+    /// Sets modified by other threads while writing may be written or not.
+    pub fn snapshot(&self) -> io::Result<()> {
+        let persisted = self.writes.persist_all(&self.store, &self.cache);
+        self.counters.write_back(persisted)
+    }
+
+    /// Removes expired sets from the cache, writing the modified ones.
+    pub fn purge_expired(&self) -> io::Result<()> {
+        self.drop_with(|| self.cache.purge_expired())
+    }
+
+    /// Runs `f` while no other handle updates `key`.
+    fn exclusive<T, F>(&self, key: &[u8], f: F) -> io::Result<T>
+    where
+        F: FnOnce() -> io::Result<T>,
+    {
+        let _guard = self.writes.keys.lock(key);
+        f()
+    }
+
+    /// Returns the set of `key`, loading it if it is not cached.
+    /// Threads missing the same key meanwhile share the set loaded.
+    fn load(&self, key: &[u8]) -> io::Result<Option<Arc<bits::Set>>> {
+        if let Some(ptr) = self.cache.get(key) {
+            return Ok(Some(ptr));
+        }
+        let leader = match self.flights.join(key) {
+            Joined::Leader(leader) => leader,
+            Joined::Loaded(loaded) => return loaded,
+        };
+        let loaded = self.fetch(key);
+        leader.land(&loaded);
+        loaded
+    }
+
+    fn fetch(&self, key: &[u8]) -> io::Result<Option<Arc<bits::Set>>> {
+        loop {
+            let written = {
+                let pending = self.writes.pending.lock(key);
+                if let Some(ptr) = pending.sets.get(key) {
+                    return Ok(Some(Arc::clone(ptr)));
+                }
+                pending.written
+            };
+            if self.absent.lock().get(key).is_some() {
+                return Ok(None);
+            }
+            let set = match self.take_serialized(key) {
+                Some(bytes) => Some(self.decode(&bytes)?),
+                None => self.store_get(key)?,
+            };
+
+            // The set read may already be replaced, pending or in the store.
+            let stale = |pending: &Pending| pending.sets.contains_key(key) || pending.written != written;
+            let set = match set {
+                Some(set) => set,
+                None => {
+                    // Puts forget absent keys once they cached the set, which is
+                    // then cached, or pending before it leaves the cache.
+                    let mut absent = self.absent.lock();
+                    if let Some(ptr) = self.cache.get(key) {
+                        return Ok(Some(ptr));
+                    }
+                    if stale(&self.writes.pending.lock(key)) {
+                        continue;
+                    }
+                    absent.put(key.to_vec(), ());
+                    return Ok(None);
+                }
+            };
+            // Sets leave the cache as pending under the shard lock `f` is called with.
+            let mut retry = false;
+            let (ptr, dropped) = self.cache.get_or_try_insert_with(key, || {
+                retry = stale(&self.writes.pending.lock(key));
+                Ok::<_, io::Error>(if retry { None } else { Some(Arc::new(set)) })
+            })?;
+            self.write_back(self.pend(dropped))?;
+            if !retry {
+                return Ok(ptr);
+            }
+        }
+    }
+
+    fn cache_put(&self, key: &[u8], ptr: Arc<bits::Set>) -> io::Result<()> {
+        self.forget_serialized(key);
+        let dropped = self.cache.write(key.to_vec(), Arc::clone(&ptr));
+        self.writes.supersede(key, &ptr);
+        // After caching the set, so that readers finding no set no longer record it absent.
+        self.absent.lock().remove(key);
+        self.write_back(self.pend(dropped))
+    }
+
+    /// Writes back the modified sets `f` drops from the cache, see `cache_put`.
+    fn drop_with<F>(&self, f: F) -> io::Result<()>
+    where
+        F: FnOnce() -> Vec<cache::Dropped<Bytes, Arc<bits::Set>>>,
+    {
+        self.write_back(self.pend(f()))
+    }
+
+    /// Keeps the sets of `dropped` evicted from the cache in the serialized tier,
+    /// and returns the modified ones, already pending. They are written before
+    /// they are read from the tier, as pending sets are read first.
+    fn pend(&self, dropped: Vec<cache::Dropped<Bytes, Arc<bits::Set>>>) -> Vec<(Bytes, Arc<bits::Set>)> {
+        for out in &dropped {
+            self.serialize(out);
+        }
+        dropped.into_iter().filter(|out| out.dirty).map(|out| (out.key, out.value)).collect()
+    }
+
+    /// Writes the pending sets of `dropped`. If one fails, the rest are still written,
+    /// and the failed one stays pending until the next snapshot.
+    fn write_back(&self, dropped: Vec<(Bytes, Arc<bits::Set>)>) -> io::Result<()> {
+        let mut result = Ok(());
+        for (key, ptr) in dropped {
+            let put = self.writes.persist(&self.store, &self.cache, &key, &ptr);
+            result = result.and(self.counters.write_back(put));
+        }
+        result
+    }
+
+    /// Removes `purged` from the set of `key` read from the store.
+    /// If the set got cached since it was read, the cached one is updated instead.
+    fn purge_stored(&self, key: &[u8], _: &bits::Set, purged: &bits::Set) -> io::Result<()> {
+        self.exclusive(key, || {
+            let pending = self.writes.pending.lock(key).sets.contains_key(key);
+            if pending || self.cache.exists(key) {
+                self.modify(key, |set| purge_set(set, purged))?;
+                return Ok(());
+            }
+            let _guard = self.writes.store.lock(key);
+            if let Some(set) = self.store_get(key)? {
+                if intersects(&set, purged) {
+                    self.store_put(key, &difference(&set, purged))?;
+                }
+            }
+            self.writes.pending.lock(key).written += 1;
+            // Readers may have cached the set before it was written.
+            self.cache.remove(key);
+            self.forget_serialized(key);
+            Ok(())
+        })
+    }
+}
+
+/// Writes modified sets, see `Index::close`.
+impl<S, H> Drop for Index<S, H>
+where
+    S: Backend,
+    H: BuildHasher,
+{
+    fn drop(&mut self) {
+        if !self.closed {
+            if let Err(err) = self.snapshot() {
+                self.on_drop.handle(&err);
+            }
+        }
+    }
+}
+
+/// The last handle dropped writes modified sets, see `SharedIndex::close`.
+impl<S, H> Drop for SharedIndex<S, H>
+where
+    S: Backend,
+    H: BuildHasher,
+{
+    fn drop(&mut self) {
+        if self.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            if let Err(err) = self.snapshot() {
+                if !self.closed {
+                    self.on_drop.handle(&err);
+                }
+            }
+        }
+    }
+}
+
+fn is_empty(set: &bits::Set) -> bool {
+    set.bits().next().is_none()
+}
+
+fn intersects(set: &bits::Set, that: &bits::Set) -> bool {
+    set.and(that).bits().next().is_some()
+}
+
+fn intersection_len(set: &bits::Set, that: &bits::Set) -> u64 {
+    set.and(that).bits().count() as u64
+}
+
+fn intersection(set: &bits::Set, that: &bits::Set) -> bits::Set {
+    set.and(that).bits().collect()
+}
+
+pub(crate) fn union(set: &bits::Set, that: &bits::Set) -> bits::Set {
+    set.or(that).bits().collect()
+}
+
+pub(crate) fn difference(set: &bits::Set, that: &bits::Set) -> bits::Set {
+    set.and_not(that).bits().collect()
+}
+
+fn purge_set(set: &mut bits::Set, purged: &bits::Set) -> bool {
+    if intersects(set, purged) {
+        *set = difference(set, purged);
+        true
+    } else {
+        false
+    }
+}
+
+fn write_key<W: Write>(w: &mut W, key: &[u8]) -> io::Result<()> {
+    w.write_all(&(key.len() as u32).to_le_bytes())?;
+    w.write_all(key)
+}
+
+fn read_keys<P: AsRef<Path>>(path: P) -> io::Result<Vec<Bytes>> {
+    let mut r = BufReader::new(File::open(path)?);
+    let mut keys = Vec::new();
+    let mut word = [0; 4];
+    loop {
+        match r.read_exact(&mut word) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(keys),
+            Err(err) => return Err(err),
+        }
+        let mut key = vec![0; u32::from_le_bytes(word) as usize];
+        r.read_exact(&mut key)?;
+        keys.push(key);
+    }
+}
+
+/// Keeps the `k` largest counts, preferring smaller keys among equal counts.
+type TopK = BinaryHeap<Reverse<(u64, Reverse<Bytes>)>>;
+
+fn offer(top: &mut TopK, k: usize, key: Bytes, count: u64) {
+    top.push(Reverse((count, Reverse(key))));
+    if top.len() > k {
+        top.pop();
+    }
+}
+
+/// Returns the count a key must exceed to enter a full `top`.
+fn floor(top: &TopK, k: usize) -> Option<u64> {
+    if top.len() < k {
+        None
+    } else {
+        top.peek().map(|&Reverse((count, _))| count)
+    }
 }
 
 macro_rules! impls {
-    ( $this:ident, $name:ident, $ptr:ident ) => {
+    ( $this:ident, $name:ident, $ptr:ident, $lock:ident, $read:ident, $write:ident ) => {
         impl<S, H> $this<S, H>
         where
-            S: Borrow<Store>,
+            S: Backend,
             H: BuildHasher,
         {
             pub fn new(store: S, raw: cache::Raw<Bytes, $ptr<bits::Set>, H>) -> Self {
@@ -46,73 +778,559 @@ macro_rules! impls {
                         cache::$name::new(raw)
                     }
                 };
-                Self { store, cache }
+                Self::from_parts(store, cache)
+            }
+
+            /// Sets what to do if writing modified sets fails when the index is dropped.
+            /// The default ignores the error.
+            pub fn set_on_drop_error(&mut self, on_drop: OnDropError) {
+                self.on_drop = on_drop;
             }
 
+            /// Writes modified sets and closes the index.
+            ///
+            /// Dropping an index also writes modified sets,
+            /// but can only report or panic on failure, see `set_on_drop_error`.
+            pub fn close(mut self) -> io::Result<()> {
+                self.closed = true;
+                self.snapshot()
+            }
+
+            /// Returns the set of `key`, excluding deleted documents.
             pub fn get<T>(&self, key: T) -> io::Result<Option<$ptr<bits::Set>>>
             where
                 T: AsRef<[u8]>,
             {
-                let key_ref = key.as_ref();
-                if let Some(set_ptr) = self.cache.get(key_ref) {
-                    return Ok(Some(set_ptr));
+                let set = match self.get_including_deleted(key)? {
+                    Some(set) => set,
+                    None => return Ok(None),
+                };
+                match self.deleted()? {
+                    Some(ref deleted) if intersects(&set, deleted) => {
+                        Ok(Some($ptr::new(difference(&set, deleted))))
+                    }
+                    _ => Ok(Some(set)),
+                }
+            }
+
+            /// Returns the set of `key` as stored, including deleted documents.
+            pub fn get_including_deleted<T>(&self, key: T) -> io::Result<Option<$ptr<bits::Set>>>
+            where
+                T: AsRef<[u8]>,
+            {
+                self.load(key.as_ref())
+            }
+
+            pub fn put<T>(&self, key: T, set: bits::Set) -> io::Result<()>
+            where
+                T: AsRef<[u8]>,
+            {
+                let key = key.as_ref();
+                check_key(key)?;
+                self.exclusive(key, || self.cache_put(key, $ptr::new(set)))
+            }
+
+            /// Applies `f` to the set of `key`, or to an empty set if there is none,
+            /// and keeps the result if `f` returns `true`. Returns what `f` returns.
+            ///
+            /// Updates of the same key through clones of a `SharedIndex` are applied one at a time.
+            pub fn update<T, F>(&self, key: T, f: F) -> io::Result<bool>
+            where
+                T: AsRef<[u8]>,
+                F: FnOnce(&mut bits::Set) -> bool,
+            {
+                let key = key.as_ref();
+                check_key(key)?;
+                self.exclusive(key, || self.modify(key, f))
+            }
+
+            /// Adds the document `id` to the set of `key`, and returns `true` if it was not present.
+            pub fn insert<T>(&self, key: T, id: u32) -> io::Result<bool>
+            where
+                T: AsRef<[u8]>,
+            {
+                self.update(key, |set| set.insert(id))
+            }
+
+            /// Removes the document `id` from the set of `key`, and returns `true` if it was present.
+            pub fn remove<T>(&self, key: T, id: u32) -> io::Result<bool>
+            where
+                T: AsRef<[u8]>,
+            {
+                self.update(key, |set| set.remove(id))
+            }
+
+            /// Adds the document `id` to the sets of its field values.
+            pub fn add_document(&self, schema: &Schema, id: u32, doc: &Document) -> io::Result<()> {
+                for key in schema.keys(doc)? {
+                    self.insert(key, id)?;
+                }
+                Ok(())
+            }
+
+            /// Returns the documents whose field `name` has `value`.
+            pub fn find(&self, schema: &Schema, name: &str, value: &Value) -> io::Result<bits::Set> {
+                let field = schema.field_of(name)?;
+                let (width, n) = match (field.kind, value) {
+                    (Kind::Numeric { bits }, &Value::Numeric(n)) => (bits, n),
+                    _ => {
+                        let key = schema.key(name, value)?;
+                        return Ok(self.get(key)?.map_or_else(bits::Set::new, |ptr| (*ptr).clone()));
+                    }
+                };
+
+                // Bit-sliced lookup: narrow the documents having the field bit by bit.
+                let mut set = match self.get(field.exists_key())? {
+                    Some(ptr) => (*ptr).clone(),
+                    None => return Ok(bits::Set::new()),
+                };
+                for i in 0..width {
+                    let slice = self.get(field.slice_key(i))?;
+                    set = match slice {
+                        Some(ref slice) if n >> i & 1 == 1 => intersection(&set, slice),
+                        Some(ref slice) => difference(&set, slice),
+                        None if n >> i & 1 == 1 => return Ok(bits::Set::new()),
+                        None => set,
+                    };
+                }
+                Ok(set)
+            }
+
+            /// Returns at most `top_k` keys under `prefix` with the number of documents
+            /// they share with `filter`, in descending order of the number.
+            /// Keys sharing no documents and deleted documents are not counted.
+            ///
+            /// Cached sets are used as they are, and stored sets whose cardinality
+            /// can not reach the current top `top_k` are not read.
+            pub fn facet_counts<T>(
+                &self,
+                prefix: T,
+                filter: &bits::Set,
+                top_k: usize,
+            ) -> io::Result<Vec<(Bytes, u64)>>
+            where
+                T: AsRef<[u8]>,
+            {
+                let prefix = prefix.as_ref();
+                if top_k == 0 {
+                    return Ok(Vec::new());
+                }
+                let filter = match self.deleted()? {
+                    Some(ref deleted) if intersects(filter, deleted) => {
+                        Cow::Owned(difference(filter, deleted))
+                    }
+                    _ => Cow::Borrowed(filter),
+                };
+                let limit = cardinality(&filter);
+
+                let mut top = TopK::with_capacity(top_k + 1);
+                let mut cached = HashSet::new();
+                self.cache.for_each(|(key, ptr)| -> io::Result<()> {
+                    if key.starts_with(prefix) {
+                        cached.insert(key.clone());
+                        offer(&mut top, top_k, key.clone(), intersection_len(ptr, &filter));
+                    }
+                    Ok(())
+                })?;
+
+                for key in self.store.keys_from(prefix) {
+                    let key = key?;
+                    if !key.starts_with(prefix) {
+                        break;
+                    }
+                    if cached.contains(&key) {
+                        continue;
+                    }
+                    if let Some(min) = floor(&top, top_k) {
+                        let bound = self.store.count(&key)?.unwrap_or(0).min(limit);
+                        if bound <= min {
+                            continue;
+                        }
+                    }
+                    if let Some(set) = self.store_get(&key)? {
+                        let count = intersection_len(&set, &filter);
+                        offer(&mut top, top_k, key, count);
+                    }
+                }
+
+                let mut counts = top.into_iter()
+                    .map(|Reverse((count, Reverse(key)))| (key, count))
+                    .filter(|&(_, count)| count > 0)
+                    .collect::<Vec<_>>();
+                counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+                Ok(counts)
+            }
+
+            /// Returns the set of deleted documents.
+            pub fn deleted(&self) -> io::Result<Option<$ptr<bits::Set>>> {
+                if let Some(ref deleted) = *self.deleted.$read() {
+                    return Ok(deleted.clone());
+                }
+                let mut deleted = self.deleted.$write();
+                if deleted.is_none() {
+                    *deleted = Some(self.store_get(DELETED)?.map($ptr::new));
+                }
+                Ok(deleted.as_ref().unwrap().clone())
+            }
+
+            /// Marks the document `id` as deleted, and returns `true` if it was not yet deleted.
+            /// Deleted documents are excluded from `get` until they are purged.
+            pub fn delete(&self, id: u32) -> io::Result<bool> {
+                self.deleted()?;
+                let mut deleted = self.deleted.$write();
+                let mut set = match *deleted {
+                    Some(Some(ref ptr)) => (**ptr).clone(),
+                    _ => bits::Set::new(),
+                };
+                if !set.insert(id) {
+                    return Ok(false);
+                }
+                self.store_put(DELETED, &set)?;
+                *deleted = Some(Some($ptr::new(set)));
+                Ok(true)
+            }
+
+            /// Removes deleted documents from every set, then clears them from the
+            /// deleted-documents set. Returns the purged documents.
+            pub fn purge(&self) -> io::Result<bits::Set> {
+                let purged = match self.deleted()? {
+                    Some(ptr) => (*ptr).clone(),
+                    None => return Ok(bits::Set::new()),
+                };
+
+                let mut cached = Vec::new();
+                self.cache.for_each(|(key, ptr)| -> io::Result<()> {
+                    if intersects(ptr, &purged) {
+                        cached.push(key.clone());
+                    }
+                    Ok(())
+                })?;
+                for key in cached {
+                    self.update(&key, |set| purge_set(set, &purged))?;
                 }
 
-                if let Some(set) = self.store.borrow().get(key_ref)? {
-                    let ptr = $ptr::new(set);
-                    let dropped = self.cache.put(key_ref.to_vec(), ptr.clone());
-                    if let Some(dropped) = dropped {
-                        self.store
-                            .borrow()
-                            .put(dropped.0, &dropped.1)?;
+                for entry in self.store.iter_from(b"") {
+                    let (key, set) = entry?;
+                    if intersects(&set, &purged) {
+                        self.purge_stored(&key, &set, &purged)?;
                     }
-                    Ok(Some(ptr))
+                }
+
+                // Documents deleted while purging remain deleted.
+                let mut deleted = self.deleted.$write();
+                let rest = match *deleted {
+                    Some(Some(ref ptr)) => difference(ptr, &purged),
+                    _ => bits::Set::new(),
+                };
+                self.store_put(DELETED, &rest)?;
+                *deleted = Some(if is_empty(&rest) {
+                    None
                 } else {
-                    Ok(None)
+                    Some($ptr::new(rest))
+                });
+                Ok(purged)
+            }
+
+            fn modify<F>(&self, key: &[u8], f: F) -> io::Result<bool>
+            where
+                F: FnOnce(&mut bits::Set) -> bool,
+            {
+                let mut ptr = self.get_including_deleted(key)?
+                    .unwrap_or_else(|| $ptr::new(bits::Set::new()));
+                if !f($ptr::make_mut(&mut ptr)) {
+                    return Ok(false);
                 }
+                self.cache_put(key, ptr)?;
+                Ok(true)
+            }
+
+            /// Sets how many keys without a set are remembered, so that looking them up
+            /// again does not read the store. `0` disables it. The default is 1024.
+            ///
+            /// Keys are forgotten when they are put through the index,
+            /// so sets written to the store by other means may not be seen.
+            pub fn set_absent_capacity(&self, cap: usize) {
+                self.absent.$lock().set_capacity(cap);
+            }
+
+            /// Writes the keys of the cached sets to `path`, least recently used first,
+            /// for `warm_up_from` to load them again after a restart.
+            /// The keys of a sharded cache are written one shard after another.
+            pub fn save_hot_keys<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+                let mut w = BufWriter::new(File::create(path)?);
+                self.cache.for_each(|(key, _)| write_key(&mut w, key))?;
+                w.flush()
             }
 
-            pub fn put<T>(&mut self, key: T, set: bits::Set) -> io::Result<()>
+            /// Loads the sets of `keys` into the cache in order, so that the last ones
+            /// are evicted last. Stops once `budget` is spent, if given.
+            /// Returns the number of sets loaded or already cached.
+            pub fn warm_up<I>(&self, keys: I, budget: Option<Duration>) -> io::Result<usize>
+            where
+                I: IntoIterator,
+                I::Item: AsRef<[u8]>,
+            {
+                let deadline = budget.map(|budget| Instant::now() + budget);
+                let mut loaded = 0;
+                for key in keys {
+                    if deadline.map_or(false, |t| Instant::now() >= t) {
+                        break;
+                    }
+                    if self.get_including_deleted(key)?.is_some() {
+                        loaded += 1;
+                    }
+                }
+                Ok(loaded)
+            }
+
+            /// Loads the sets of the keys saved by `save_hot_keys`, see `warm_up`.
+            pub fn warm_up_from<P>(&self, path: P, budget: Option<Duration>) -> io::Result<usize>
+            where
+                P: AsRef<Path>,
+            {
+                self.warm_up(read_keys(path)?, budget)
+            }
+
+            /// Loads the set of `key` if necessary, and keeps it cached until it is unpinned.
+            /// Returns `false` if there is no such set, or it could not be cached.
+            ///
+            /// Pinned sets count toward the capacity of the cache. Once it is full of them,
+            /// other sets are written through to the store instead of being cached.
+            pub fn pin<T>(&self, key: T) -> io::Result<bool>
+            where
+                T: AsRef<[u8]>,
+            {
+                let key = key.as_ref();
+                if self.get_including_deleted(key)?.is_none() {
+                    return Ok(false);
+                }
+                Ok(self.cache.pin(key))
+            }
+
+            /// Lets the set of `key` be evicted again. Returns `false` if it was not pinned.
+            pub fn unpin<T>(&self, key: T) -> bool
             where
                 T: AsRef<[u8]>,
             {
-                self.cache_put(key, $ptr::new(set))
+                self.cache.unpin(key.as_ref())
+            }
+
+            /// Returns the number of cached sets.
+            pub fn cache_len(&self) -> usize {
+                self.cache.len()
+            }
+
+            /// Returns the number of sets the cache can hold.
+            pub fn cache_capacity(&self) -> usize {
+                self.cache.capacity()
+            }
+
+            /// Sets the number of sets the cache can hold, at least one,
+            /// writing the modified sets evicted.
+            pub fn set_cache_capacity(&self, cap: usize) -> io::Result<()> {
+                self.drop_with(|| self.cache.set_capacity(cap.max(1)))
+            }
+
+            /// Checks if the set of `key` is cached.
+            pub fn is_cached<T>(&self, key: T) -> bool
+            where
+                T: AsRef<[u8]>,
+            {
+                self.cache.exists(key.as_ref())
+            }
+
+            /// Returns the key of the least recently used set.
+            pub fn cache_lru(&self) -> Option<Bytes> {
+                self.cache.lru().map(|(key, _)| key)
             }
 
-            pub fn snapshot(&self) -> io::Result<()> {
-                self.cache.for_each(|(key, ptr)| {
-                    self.store_put(key, &*ptr)
-                })
+            /// Returns the keys of the cached sets, least recently used first.
+            pub fn cached_keys(&self) -> Vec<Bytes> {
+                self.cache.entries().into_iter().map(|(key, _)| key).collect()
             }
 
-            fn cache_put<T>(&self, key: T, ptr: $ptr<bits::Set>) -> io::Result<()>
+            /// Removes the set of `key` from the cache, writing it if modified.
+            /// Returns `false` if it was not cached.
+            pub fn evict<T>(&self, key: T) -> io::Result<bool>
             where
                 T: AsRef<[u8]>,
             {
-                let key_ref = key.as_ref();
-                if let Some(out) = self.cache.put(key_ref.to_vec(), ptr) {
-                    self.store
-                        .borrow()
-                        .put(out.0, &out.1)?;
+                let key = key.as_ref();
+                let mut cached = false;
+                self.drop_with(|| {
+                    let out = self.cache.remove(key);
+                    cached = out.is_some();
+                    out.into_iter().collect()
+                })?;
+                Ok(cached)
+            }
+
+            /// Removes every set from the cache, writing the modified ones.
+            pub fn clear_cache(&self) -> io::Result<()> {
+                self.drop_with(|| self.cache.clear())
+            }
+
+            /// Keeps sets evicted from the cache in `tier`, serialized as in the store.
+            /// The tier is looked up before the store, so that reading the sets again
+            /// only takes decoding them. Being compact, it can hold many more sets
+            /// than the cache, e.g. with a maximum weight in bytes.
+            pub fn set_serialized_tier(&self, tier: cache::Raw<Bytes, Bytes>) {
+                *self.serialized.$lock() = Some(tier);
+            }
+
+            /// Returns the counters of the serialized tier, all zero if there is none.
+            pub fn serialized_stats(&self) -> cache::Stats {
+                self.serialized
+                    .$lock()
+                    .as_ref()
+                    .map_or_else(cache::Stats::default, |tier| tier.stats())
+            }
+
+            /// Keeps the set of `out` in the serialized tier if it was evicted.
+            fn serialize(&self, out: &cache::Dropped<Bytes, $ptr<bits::Set>>) {
+                if !out.cause.is_eviction() || self.serialized.$lock().is_none() {
+                    return;
+                }
+                // Encoded without holding the tier, which other handles look up meanwhile.
+                // Encoding into memory does not fail.
+                let bytes = match encode(&out.value) {
+                    Ok(bytes) => bytes,
+                    Err(_) => return,
+                };
+                if let Some(ref mut tier) = *self.serialized.$lock() {
+                    tier.load(out.key.clone(), bytes);
                 }
-                Ok(())
+            }
+
+            /// Removes the serialized set of `key` from the tier, and returns it.
+            fn take_serialized(&self, key: &[u8]) -> Option<Bytes> {
+                let mut guard = self.serialized.$lock();
+                let bytes = match *guard {
+                    Some(ref mut tier) => {
+                        if tier.get(key).is_some() {
+                            tier.remove(key)
+                        } else {
+                            None
+                        }
+                    }
+                    None => None,
+                };
+                bytes
+            }
+
+            fn forget_serialized(&self, key: &[u8]) {
+                if let Some(ref mut tier) = *self.serialized.$lock() {
+                    tier.remove(key);
+                }
+            }
+
+            /// Returns the counters of the cache.
+            pub fn cache_stats(&self) -> cache::Stats {
+                self.cache.stats()
+            }
+
+            /// Returns the counters of the store accesses.
+            pub fn store_stats(&self) -> StoreStats {
+                self.counters.stats()
+            }
+
+            /// Resets the counters of the cache, the serialized tier and the store accesses.
+            pub fn reset_stats(&self) {
+                self.cache.reset_stats();
+                if let Some(ref mut tier) = *self.serialized.$lock() {
+                    tier.reset_stats();
+                }
+                self.counters.reset();
+            }
+
+            fn store_get(&self, key: &[u8]) -> io::Result<Option<bits::Set>> {
+                let sized = self.store.get_sized(key)?;
+                self.counters.read(sized.as_ref().map(|&(_, len)| len));
+                Ok(sized.map(|(set, _)| set))
+            }
+
+            /// Decodes a set from the serialized tier.
+            fn decode(&self, bytes: &[u8]) -> io::Result<bits::Set> {
+                let set = decode(bytes)?;
+                self.counters.decoded(bytes.len());
+                Ok(set)
             }
 
             fn store_put<T>(&self, key: T, set: &bits::Set) -> io::Result<()>
             where
                 T: AsRef<[u8]>,
             {
-                self.store
-                    .borrow()
-                    .put(key, set)
+                self.store.put(key.as_ref(), set)
             }
+        }
 
+        impl<S, H> $this<S, H>
+        where
+            S: Backend + Borrow<Store>,
+            H: BuildHasher,
+        {
             pub fn seek(&self) -> Seek {
                 self.store.borrow().seek()
             }
+
+            /// Writes every set, including the modified ones not yet written,
+            /// into a segment file.
+            pub fn write_segment<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+                self.snapshot()?;
+                file::Writer::write_store(self.store.borrow(), path)
+            }
         }
     }
 }
 
-impls!(Index, Single, Rc);
-impls!(SharedIndex, Shared, Arc);
+impls!(Index, Single, Rc, borrow_mut, borrow, borrow_mut);
+impls!(SharedIndex, Shared, Arc, lock, read, write);
+
+impl<S, H> SharedIndex<S, H>
+where
+    S: Backend + Clone + Send + 'static,
+    H: BuildHasher + Send + 'static,
+{
+    /// Runs `purge` on a background thread.
+    pub fn purge_in_background(&self) -> thread::JoinHandle<io::Result<bits::Set>> {
+        let this = self.clone();
+        thread::spawn(move || this.purge())
+    }
+
+    /// Runs `warm_up_from` on a background thread, so that the index serves
+    /// requests while its cache fills up.
+    pub fn warm_up_in_background<P>(
+        &self,
+        path: P,
+        budget: Option<Duration>,
+    ) -> thread::JoinHandle<io::Result<usize>>
+    where
+        P: Into<PathBuf>,
+    {
+        let (this, path) = (self.clone(), path.into());
+        thread::spawn(move || this.warm_up_from(path, budget))
+    }
+
+    /// Starts a thread writing modified sets to the store as `policy` says.
+    /// Errors are passed to `on_error`, and the thread keeps running.
+    ///
+    /// The thread does not count as a handle, so the last handle dropped
+    /// still flushes the cache.
+    ///
+    /// With `max_dirty_bytes`, a cache weighing nothing starts weighing sets
+    /// by their size in memory, so that polling does not measure every set.
+    pub fn start_flusher<F>(&self, policy: FlushPolicy, on_error: F) -> Flusher
+    where
+        F: FnMut(io::Error) + Send + 'static,
+    {
+        if policy.max_dirty_bytes.is_some() {
+            self.cache.weigh_in_memory();
+        }
+        let cache = self.cache.clone();
+        let dirty = move || cache.dirty_weight();
+        let (store, cache, writes) = (self.store.clone(), self.cache.clone(), Arc::clone(&self.writes));
+        let counters = Arc::clone(&self.counters);
+        let flush = move || counters.write_back(writes.persist_all(&store, &cache));
+        Flusher::spawn(policy, dirty, flush, on_error)
+    }
+}
@@ -5,18 +5,34 @@ extern crate compacts;
 extern crate compacts;
 
 extern crate linked_hash_map;
+extern crate memmap;
 extern crate parking_lot;
 extern crate rocksdb;
 
 pub mod cache;
+pub mod file;
 
 mod store;
 mod index;
+mod builder;
+mod dict;
+mod schema;
+mod partition;
+mod merge;
+mod page;
+mod flush;
 #[cfg(test)]
 mod tests;
 
 pub use compacts::bits;
-pub use store::{Seek, Store};
-pub use index::{Index, SharedIndex};
+pub use store::{Backend, Range, Seek, Store};
+pub use index::{Index, OnDropError, SharedIndex, StoreStats};
+pub use builder::{Builder, Phase, Progress};
+pub use dict::Dictionary;
+pub use partition::Partitioned;
+pub use merge::{merge, Remap};
+pub use page::{Ids, Page};
+pub use flush::{FlushPolicy, Flusher};
+pub use schema::{Document, Field, Kind, Resolution, Schema, Value};
 
 pub type Bytes = Vec<u8>;
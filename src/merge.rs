@@ -0,0 +1,104 @@
+use std::io;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use compacts::bits;
+use super::{Bytes, Range, Store};
+use super::index::{union, DELETED};
+
+/// Maps the document ids of an input store to the ids of the merged store.
+#[derive(Debug, Clone, Copy)]
+pub enum Remap<'a> {
+    /// Adds the offset to every id.
+    Offset(u32),
+    /// Replaces the id `i` with `table[i]`.
+    Table(&'a [u32]),
+}
+
+impl<'a> Remap<'a> {
+    fn id(&self, id: u32) -> io::Result<u32> {
+        let mapped = match *self {
+            Remap::Offset(offset) => id.checked_add(offset),
+            Remap::Table(table) => table.get(id as usize).cloned(),
+        };
+        mapped.ok_or_else(|| {
+            let msg = format!("id {} can not be remapped by {:?}", id, self);
+            io::Error::new(io::ErrorKind::InvalidInput, msg)
+        })
+    }
+
+    fn apply(&self, set: &bits::Set) -> io::Result<bits::Set> {
+        let mut mapped = bits::Set::new();
+        for id in set.bits() {
+            mapped.insert(self.id(id)?);
+        }
+        Ok(mapped)
+    }
+}
+
+/// Merges `inputs` into `output`.
+///
+/// The set of each key in `output` is the union of the remapped sets of that key
+/// in every input, and so are the deleted documents. Inputs are read in key order
+/// one entry at a time. Dictionaries and schemas are not merged.
+pub fn merge(inputs: &[(&Store, Remap)], output: &Store) -> io::Result<()> {
+    let mut ranges: Vec<Range> = Vec::with_capacity(inputs.len());
+    let mut heads = Vec::with_capacity(inputs.len());
+    let mut heap = BinaryHeap::with_capacity(inputs.len());
+    for (i, &(store, _)) in inputs.iter().enumerate() {
+        let mut range = store.seek().iter_from("");
+        if let Some(entry) = range.next() {
+            let (key, set) = entry?;
+            heap.push(Reverse((key, i)));
+            heads.push(Some(set));
+        } else {
+            heads.push(None);
+        }
+        ranges.push(range);
+    }
+
+    while let Some(Reverse((key, i))) = heap.pop() {
+        let mut merged = pop(&mut ranges, &mut heads, &mut heap, i, &inputs[i].1)?;
+        loop {
+            let j = match heap.peek() {
+                Some(&Reverse((ref next, j))) if *next == key => j,
+                _ => break,
+            };
+            heap.pop();
+            let set = pop(&mut ranges, &mut heads, &mut heap, j, &inputs[j].1)?;
+            merged = union(&merged, &set);
+        }
+        output.put(&key, &merged)?;
+    }
+
+    let mut deleted = None;
+    for &(store, ref remap) in inputs {
+        if let Some(set) = store.get(DELETED)? {
+            let set = remap.apply(&set)?;
+            deleted = Some(match deleted {
+                Some(ref acc) => union(acc, &set),
+                None => set,
+            });
+        }
+    }
+    if let Some(deleted) = deleted {
+        output.put(DELETED, &deleted)?;
+    }
+    Ok(())
+}
+
+/// Takes the head set of input `i` remapped, and advances the input.
+fn pop(
+    ranges: &mut [Range],
+    heads: &mut [Option<bits::Set>],
+    heap: &mut BinaryHeap<Reverse<(Bytes, usize)>>,
+    i: usize,
+    remap: &Remap,
+) -> io::Result<bits::Set> {
+    let set = heads[i].take().expect("input head");
+    if let Some(entry) = ranges[i].next() {
+        let (key, next) = entry?;
+        heap.push(Reverse((key, i)));
+        heads[i] = Some(next);
+    }
+    remap.apply(&set)
+}
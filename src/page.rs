@@ -0,0 +1,183 @@
+use std::fmt;
+use std::iter;
+use std::vec;
+use compacts::bits::{self, PopCount, Rank, Select1};
+
+/// A window over the ids of a set.
+///
+/// The first id of a page is located by rank and select, and the page is
+/// then read by `bits()` iteration from it, so no id costs a select.
+/// To page with a cursor, pass the last id of a page to `after` to get
+/// the next one.
+#[derive(Debug, Clone)]
+pub struct Page<'a> {
+    set: &'a bits::Set,
+    after: Option<u32>,
+    skip: u64,
+    limit: Option<u64>,
+    rev: bool,
+}
+
+/// An iterator over the ids of a `Page`.
+pub struct Ids<'a> {
+    // Ids of the page in ascending order, from the first one selected.
+    bits: Box<Iterator<Item = u32> + 'a>,
+    // The rest of `bits`, read at once when ids are taken from the back.
+    back: Option<vec::IntoIter<u32>>,
+    // Number of ids not yet yielded.
+    len: usize,
+    rev: bool,
+}
+
+/// Returns the number of ids less than `id`.
+fn rank(set: &bits::Set, id: u32) -> u64 {
+    set.rank1(id) as u64
+}
+
+/// Returns the id whose rank is `r`.
+fn select(set: &bits::Set, r: u64) -> u32 {
+    set.select1(r as u32).expect("rank out of bounds")
+}
+
+impl<'a> Page<'a> {
+    /// Creates a page over all ids of `set` in ascending order.
+    pub fn new(set: &'a bits::Set) -> Self {
+        Page {
+            set,
+            after: None,
+            skip: 0,
+            limit: None,
+            rev: false,
+        }
+    }
+
+    /// Starts after `id`, i.e. at the ids greater than `id`,
+    /// or less than `id` in reverse order.
+    pub fn after(mut self, id: u32) -> Self {
+        self.after = Some(id);
+        self
+    }
+
+    /// Skips the first `n` ids.
+    pub fn skip(mut self, n: u64) -> Self {
+        self.skip = n;
+        self
+    }
+
+    /// Yields at most `n` ids.
+    pub fn limit(mut self, n: u64) -> Self {
+        self.limit = Some(n);
+        self
+    }
+
+    /// Yields ids in descending order.
+    pub fn rev(mut self) -> Self {
+        self.rev = true;
+        self
+    }
+
+    pub fn iter(&self) -> Ids<'a> {
+        let total = self.set.count1() as u64;
+        let limit = self.limit.unwrap_or(total);
+        let (lo, hi) = if self.rev {
+            let end = self.after.map_or(total, |id| rank(self.set, id));
+            let hi = end.saturating_sub(self.skip);
+            (hi.saturating_sub(limit), hi)
+        } else {
+            let start = self.after.map_or(0, |id| {
+                rank(self.set, id) + self.set.get(id) as u64
+            });
+            let lo = start.saturating_add(self.skip).min(total);
+            (lo, lo.saturating_add(limit).min(total))
+        };
+        let len = (hi - lo) as usize;
+        let bits: Box<Iterator<Item = u32> + 'a> = if len == 0 {
+            Box::new(iter::empty())
+        } else {
+            let first = select(self.set, lo);
+            Box::new(self.set.bits().skip_while(move |&id| id < first).take(len))
+        };
+        Ids {
+            bits,
+            back: None,
+            len,
+            rev: self.rev,
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u32> {
+        self.iter().collect()
+    }
+}
+
+impl<'a, 'b> IntoIterator for &'b Page<'a> {
+    type Item = u32;
+    type IntoIter = Ids<'a>;
+    fn into_iter(self) -> Ids<'a> {
+        self.iter()
+    }
+}
+
+impl<'a> Ids<'a> {
+    /// Returns the remaining ids in ascending order, reading them at once.
+    /// Reverse pages are walked this way, bounded by the size of the page.
+    fn buffered(&mut self) -> &mut vec::IntoIter<u32> {
+        if self.back.is_none() {
+            let rest = self.bits.by_ref().take(self.len).collect::<Vec<_>>();
+            self.back = Some(rest.into_iter());
+        }
+        self.back.as_mut().unwrap()
+    }
+
+    fn front(&mut self) -> Option<u32> {
+        match self.back {
+            Some(ref mut back) => back.next(),
+            None => self.bits.next(),
+        }
+    }
+}
+
+impl<'a> fmt::Debug for Ids<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Ids")
+            .field("len", &self.len)
+            .field("rev", &self.rev)
+            .finish()
+    }
+}
+
+impl<'a> Iterator for Ids<'a> {
+    type Item = u32;
+    fn next(&mut self) -> Option<u32> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        if self.rev {
+            self.buffered().next_back()
+        } else {
+            self.front()
+        }
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+impl<'a> DoubleEndedIterator for Ids<'a> {
+    fn next_back(&mut self) -> Option<u32> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        if self.rev {
+            self.front()
+        } else {
+            self.buffered().next_back()
+        }
+    }
+}
+impl<'a> ExactSizeIterator for Ids<'a> {
+    fn len(&self) -> usize {
+        self.len
+    }
+}
@@ -0,0 +1,220 @@
+use std::io;
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops;
+use std::path::{Path, PathBuf};
+use compacts::bits;
+use super::{cache, Index, Store};
+use super::index::union;
+
+/// Marker file of a sealed partition.
+const SEALED: &str = "SEALED";
+
+/// File holding the span of a partition, in seconds.
+const SPAN: &str = "SPAN";
+
+/// An index split into time partitions, each in its own `Store` directory.
+///
+/// A partition covers `span` seconds starting at a multiple of `span`.
+/// Writes are routed by timestamp, and queries over a time range union
+/// the results of every overlapping partition.
+#[derive(Debug)]
+pub struct Partitioned {
+    root: PathBuf,
+    span: u64,
+    cap: usize,
+    parts: BTreeMap<u64, Partition>,
+}
+
+#[derive(Debug)]
+struct Partition {
+    index: Index<Store>,
+    sealed: bool,
+}
+
+fn error_sealed(start: u64) -> io::Error {
+    let msg = format!("partition {} is sealed", start);
+    io::Error::new(io::ErrorKind::PermissionDenied, msg)
+}
+
+impl Partition {
+    fn open(dir: &Path, span: u64, cap: usize) -> io::Result<Self> {
+        let store = Store::open(dir)?;
+        check_span(dir, span)?;
+        let index = Index::new(store, cache::Raw::new(cap));
+        let sealed = dir.join(SEALED).exists();
+        Ok(Partition { index, sealed })
+    }
+}
+
+/// Writes `span` into the partition at `dir`, or checks it is the one written.
+fn check_span(dir: &Path, span: u64) -> io::Result<()> {
+    let path = dir.join(SPAN);
+    let written = match fs::read_to_string(&path) {
+        Ok(written) => written,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            return fs::write(&path, span.to_string());
+        }
+        Err(err) => return Err(err),
+    };
+    match written.trim().parse::<u64>() {
+        Ok(found) if found == span => Ok(()),
+        Ok(found) => {
+            let msg = format!("{:?} spans {} seconds, not {}", dir, found, span);
+            Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+        }
+        Err(err) => {
+            let msg = format!("invalid span in {:?}: {}", path, err);
+            Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+        }
+    }
+}
+
+impl Partitioned {
+    /// Opens the partitions under `root`, each caching at most `cap` sets.
+    /// Fails if a partition was created with another `span`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `span` is zero.
+    pub fn open<P: AsRef<Path>>(root: P, span: u64, cap: usize) -> io::Result<Self> {
+        assert!(span > 0, "span must be greater than 0");
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+
+        let mut parts = BTreeMap::new();
+        for entry in fs::read_dir(&root)? {
+            let entry = entry?;
+            let start = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+                Some(start) => start,
+                None => continue,
+            };
+            parts.insert(start, Partition::open(&entry.path(), span, cap)?);
+        }
+        Ok(Partitioned {
+            root,
+            span,
+            cap,
+            parts,
+        })
+    }
+
+    /// Returns the number of seconds a partition covers.
+    pub fn span(&self) -> u64 {
+        self.span
+    }
+
+    /// Returns the start of the partition that covers `ts`.
+    pub fn start_of(&self, ts: u64) -> u64 {
+        ts - ts % self.span
+    }
+
+    /// Returns the starts of the existing partitions in ascending order.
+    pub fn partitions(&self) -> Vec<u64> {
+        self.parts.keys().cloned().collect()
+    }
+
+    pub fn is_sealed(&self, start: u64) -> bool {
+        self.parts.get(&start).map_or(false, |p| p.sealed)
+    }
+
+    /// Creates the partition that covers `ts` if missing, and returns its start.
+    pub fn create(&mut self, ts: u64) -> io::Result<u64> {
+        let start = self.start_of(ts);
+        if !self.parts.contains_key(&start) {
+            let part = Partition::open(&self.dir(start), self.span, self.cap)?;
+            self.parts.insert(start, part);
+        }
+        Ok(start)
+    }
+
+    /// Adds the document `id` to the set of `key` in the partition that covers `ts`.
+    pub fn insert<T>(&mut self, ts: u64, key: T, id: u32) -> io::Result<bool>
+    where
+        T: AsRef<[u8]>,
+    {
+        self.writable(ts)?.insert(key, id)
+    }
+
+    /// Replaces the set of `key` in the partition that covers `ts`.
+    pub fn put<T>(&mut self, ts: u64, key: T, set: bits::Set) -> io::Result<()>
+    where
+        T: AsRef<[u8]>,
+    {
+        self.writable(ts)?.put(key, set)
+    }
+
+    /// Returns the union of the sets of `key` in the partitions overlapping `range`.
+    pub fn get<T>(&self, range: ops::Range<u64>, key: T) -> io::Result<bits::Set>
+    where
+        T: AsRef<[u8]>,
+    {
+        let key = key.as_ref();
+        let mut set = bits::Set::new();
+        if range.start >= range.end {
+            return Ok(set);
+        }
+        let first = self.start_of(range.start);
+        for part in self.parts.range(first..range.end).map(|(_, p)| p) {
+            if let Some(ptr) = part.index.get(key)? {
+                set = union(&set, &ptr);
+            }
+        }
+        Ok(set)
+    }
+
+    /// Writes the cached sets of every partition.
+    pub fn snapshot(&self) -> io::Result<()> {
+        for part in self.parts.values() {
+            part.index.snapshot()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the cached sets of the partition starting at `start`,
+    /// and rejects further writes to it.
+    pub fn seal(&mut self, start: u64) -> io::Result<()> {
+        let dir = self.dir(start);
+        let part = match self.parts.get_mut(&start) {
+            Some(part) => part,
+            None => {
+                let msg = format!("partition {} not found", start);
+                return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+            }
+        };
+        if !part.sealed {
+            part.index.snapshot()?;
+            fs::File::create(dir.join(SEALED))?;
+            part.sealed = true;
+        }
+        Ok(())
+    }
+
+    /// Removes the partition starting at `start` with all its data,
+    /// and returns `true` if it existed.
+    pub fn drop_partition(&mut self, start: u64) -> io::Result<bool> {
+        match self.parts.remove(&start) {
+            Some(part) => {
+                // Close the store before removing its files.
+                drop(part);
+                fs::remove_dir_all(self.dir(start))?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn dir(&self, start: u64) -> PathBuf {
+        self.root.join(format!("{:020}", start))
+    }
+
+    fn writable(&mut self, ts: u64) -> io::Result<&mut Index<Store>> {
+        let start = self.create(ts)?;
+        let part = self.parts.get_mut(&start).unwrap();
+        if part.sealed {
+            Err(error_sealed(start))
+        } else {
+            Ok(&mut part.index)
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::io;
+use std::collections::HashMap;
+use super::{Bytes, Store};
+
+const SCHEMA: &[u8] = b"\xffmeta/schema";
+
+const SECS_PER_HOUR: u64 = 60 * 60;
+const SECS_PER_DAY: u64 = 24 * SECS_PER_HOUR;
+
+/// Names and kinds of the fields of indexed documents.
+///
+/// Every field owns the keys prefixed with its name followed by a NUL byte.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Schema {
+    fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    pub kind: Kind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// One set per distinct value.
+    Keyword,
+    /// One set for `true` and one for `false`.
+    Boolean,
+    /// Unsigned integers of at most `bits` bits, one set per bit.
+    Numeric { bits: u8 },
+    /// Timestamps bucketed by the resolution, one set per bucket.
+    Date(Resolution),
+    /// Multiple keywords per document.
+    Tags,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Day,
+    Hour,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Keyword(Bytes),
+    Boolean(bool),
+    Numeric(u64),
+    /// Seconds since the Unix epoch.
+    Date(u64),
+    Tags(Vec<Bytes>),
+}
+
+/// A document to index, as a map from field names to values.
+pub type Document = HashMap<String, Value>;
+
+fn error_invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+fn error_invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Schema {
+    pub fn new() -> Self {
+        Schema::default()
+    }
+
+    /// Adds a field.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name is already used or contains a NUL byte,
+    /// or if a numeric field has zero or more than 64 bits.
+    pub fn field(mut self, name: &str, kind: Kind) -> Self {
+        assert!(!name.contains('\0'), "field name contains NUL: {:?}", name);
+        assert!(self.get(name).is_none(), "duplicate field: {:?}", name);
+        if let Kind::Numeric { bits } = kind {
+            assert!(bits > 0 && bits <= 64, "invalid numeric bits: {}", bits);
+        }
+        self.fields.push(Field {
+            name: name.to_owned(),
+            kind,
+        });
+        self
+    }
+
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Field> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+
+    /// Persists the schema into `store`, or checks that it equals the persisted one.
+    pub fn open(&self, store: &Store) -> io::Result<()> {
+        let bytes = self.encode();
+        match store.get_bytes(SCHEMA)? {
+            Some(ref stored) if *stored == bytes => Ok(()),
+            Some(stored) => {
+                let msg = format!("schema mismatch: {:?}", Schema::decode(&stored)?);
+                Err(error_invalid_data(msg))
+            }
+            None => store.put_bytes(SCHEMA, &bytes),
+        }
+    }
+
+    /// Returns the key prefix owned by the field `name`.
+    pub fn prefix(&self, name: &str) -> io::Result<Bytes> {
+        self.field_of(name).map(prefix)
+    }
+
+    /// Returns the key of `value` of the field `name`.
+    /// Numeric fields have no single key, see `Index::find`.
+    pub fn key(&self, name: &str, value: &Value) -> io::Result<Bytes> {
+        let field = self.field_of(name)?;
+        let mut keys = field.keys(value)?;
+        match field.kind {
+            Kind::Numeric { .. } => {}
+            _ if keys.len() == 1 => return Ok(keys.pop().unwrap()),
+            _ => {}
+        }
+        let msg = format!("{:?} has no single key in field {:?}", value, name);
+        Err(error_invalid_input(msg))
+    }
+
+    /// Returns the keys of the sets a document belongs to.
+    pub fn keys(&self, doc: &Document) -> io::Result<Vec<Bytes>> {
+        let mut keys = Vec::new();
+        for (name, value) in doc {
+            keys.extend(self.field_of(name)?.keys(value)?);
+        }
+        Ok(keys)
+    }
+
+    pub(crate) fn field_of(&self, name: &str) -> io::Result<&Field> {
+        self.get(name)
+            .ok_or_else(|| error_invalid_input(format!("unknown field: {:?}", name)))
+    }
+
+    fn encode(&self) -> Bytes {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(self.fields.len() as u32).to_le_bytes());
+        for field in &self.fields {
+            buf.extend_from_slice(&(field.name.len() as u32).to_le_bytes());
+            buf.extend_from_slice(field.name.as_bytes());
+            let (tag, arg) = match field.kind {
+                Kind::Keyword => (0, 0),
+                Kind::Boolean => (1, 0),
+                Kind::Numeric { bits } => (2, bits),
+                Kind::Date(Resolution::Day) => (3, 0),
+                Kind::Date(Resolution::Hour) => (3, 1),
+                Kind::Tags => (4, 0),
+            };
+            buf.push(tag);
+            buf.push(arg);
+        }
+        buf
+    }
+
+    fn decode(mut bytes: &[u8]) -> io::Result<Schema> {
+        fn take<'a>(bytes: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+            if bytes.len() < n {
+                return Err(error_invalid_data("truncated schema".to_owned()));
+            }
+            let (head, tail) = bytes.split_at(n);
+            *bytes = tail;
+            Ok(head)
+        }
+        fn take_u32(bytes: &mut &[u8]) -> io::Result<u32> {
+            let mut word = [0; 4];
+            word.copy_from_slice(take(bytes, 4)?);
+            Ok(u32::from_le_bytes(word))
+        }
+
+        let mut fields = Vec::new();
+        for _ in 0..take_u32(&mut bytes)? {
+            let len = take_u32(&mut bytes)? as usize;
+            let name = String::from_utf8(take(&mut bytes, len)?.to_vec())
+                .map_err(|err| error_invalid_data(err.to_string()))?;
+            let kind = match (take(&mut bytes, 1)?[0], take(&mut bytes, 1)?[0]) {
+                (0, _) => Kind::Keyword,
+                (1, _) => Kind::Boolean,
+                (2, bits) => Kind::Numeric { bits },
+                (3, 0) => Kind::Date(Resolution::Day),
+                (3, _) => Kind::Date(Resolution::Hour),
+                (4, _) => Kind::Tags,
+                (tag, _) => return Err(error_invalid_data(format!("unknown kind: {}", tag))),
+            };
+            fields.push(Field { name, kind });
+        }
+        Ok(Schema { fields })
+    }
+}
+
+fn prefix(field: &Field) -> Bytes {
+    let mut key = field.name.as_bytes().to_vec();
+    key.push(0);
+    key
+}
+
+fn with_suffix(mut key: Bytes, suffix: &[u8]) -> Bytes {
+    key.extend_from_slice(suffix);
+    key
+}
+
+impl Field {
+    /// Returns the key of the set of documents that have this numeric field.
+    pub(crate) fn exists_key(&self) -> Bytes {
+        prefix(self)
+    }
+
+    /// Returns the key of the set of documents whose numeric value has bit `i` set.
+    pub(crate) fn slice_key(&self, i: u8) -> Bytes {
+        with_suffix(prefix(self), &[i])
+    }
+
+    fn keys(&self, value: &Value) -> io::Result<Vec<Bytes>> {
+        let keys = match (self.kind, value) {
+            (Kind::Keyword, &Value::Keyword(ref word)) | (Kind::Tags, &Value::Keyword(ref word)) => {
+                vec![with_suffix(prefix(self), word)]
+            }
+            (Kind::Tags, &Value::Tags(ref tags)) => tags.iter()
+                .map(|tag| with_suffix(prefix(self), tag))
+                .collect(),
+            (Kind::Boolean, &Value::Boolean(b)) => vec![with_suffix(prefix(self), &[b as u8])],
+            (Kind::Numeric { bits }, &Value::Numeric(n)) => {
+                if bits < 64 && n >> bits != 0 {
+                    let msg = format!("{} overflows {} bits of field {:?}", n, bits, self.name);
+                    return Err(error_invalid_input(msg));
+                }
+                let mut keys = vec![self.exists_key()];
+                keys.extend((0..bits).filter(|&i| n >> i & 1 == 1).map(|i| self.slice_key(i)));
+                keys
+            }
+            (Kind::Date(res), &Value::Date(secs)) => {
+                let bucket = match res {
+                    Resolution::Day => secs / SECS_PER_DAY,
+                    Resolution::Hour => secs / SECS_PER_HOUR,
+                };
+                vec![with_suffix(prefix(self), &bucket.to_be_bytes())]
+            }
+            _ => {
+                let msg = format!("{:?} does not match field {:?}", value, self);
+                return Err(error_invalid_input(msg));
+            }
+        };
+        Ok(keys)
+    }
+}
@@ -1,20 +1,121 @@
 use std::io;
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::path::Path;
 use compacts::bits;
+use parking_lot::{Mutex, MutexGuard};
 use rocksdb::{self, Writable};
+use super::Bytes;
+
+/// A key-value storage of sets backing an `Index`.
+pub trait Backend {
+    /// Returns the set of the given key.
+    fn get(&self, key: &[u8]) -> io::Result<Option<bits::Set>>;
+
+    /// Returns the set of the given key with the length of its serialized form.
+    /// By default the set is encoded again to measure it.
+    fn get_sized(&self, key: &[u8]) -> io::Result<Option<(bits::Set, usize)>> {
+        match self.get(key)? {
+            Some(set) => {
+                let len = encode(&set)?.len();
+                Ok(Some((set, len)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Writes the set of the given key.
+    fn put(&self, key: &[u8], set: &bits::Set) -> io::Result<()>;
+
+    /// Returns the entries whose keys are greater than or equal to `start` in key order.
+    /// Reserved keys are skipped.
+    fn iter_from<'a>(
+        &'a self,
+        start: &[u8],
+    ) -> Box<Iterator<Item = io::Result<(Bytes, bits::Set)>> + 'a>;
+
+    /// Returns the keys greater than or equal to `start` in key order.
+    /// Reserved keys are skipped.
+    fn keys_from<'a>(&'a self, start: &[u8]) -> Box<Iterator<Item = io::Result<Bytes>> + 'a>;
+
+    /// Returns the number of documents in the set of the given key.
+    fn count(&self, key: &[u8]) -> io::Result<Option<u64>> {
+        Ok(self.get(key)?.map(|set| cardinality(&set)))
+    }
+}
 
 #[derive(Debug)]
 pub struct Store {
     db: rocksdb::DB,
+    // Serialize the read-modify-write sequences on reserved keys, by prefix.
+    locks: Vec<Mutex<()>>,
 }
 pub struct Seek<'a> {
     db: &'a rocksdb::DB,
 }
 
+/// An iterator over the entries of a `Store` in key order.
+/// Reserved keys are skipped.
+pub struct Range<'a> {
+    iter: rocksdb::DBIterator<&'a rocksdb::DB>,
+    end: Option<Vec<u8>>,
+    valid: bool,
+}
+
+/// An iterator over the keys of a `Store` in key order.
+/// Reserved keys are skipped.
+pub struct Keys<'a> {
+    iter: rocksdb::DBIterator<&'a rocksdb::DB>,
+    valid: bool,
+}
+
+/// Prefix of the keys reserved for the crate's own bookkeeping.
+pub(crate) const META: &[u8] = b"\xffmeta/";
+
+/// The least key greater than every reserved key.
+const META_END: &[u8] = b"\xffmeta0";
+
+/// Number of locks of the reserved keys of a `Store`.
+const LOCKS: usize = 8;
+
+/// Prefix of the keys of the stored cardinalities.
+const CARD: &[u8] = b"\xffmeta/card/";
+
+pub(crate) fn card_key(key: &[u8]) -> Vec<u8> {
+    let mut card = CARD.to_vec();
+    card.extend_from_slice(key);
+    card
+}
+
+/// Returns the number of documents in `set`.
+pub(crate) fn cardinality(set: &bits::Set) -> u64 {
+    set.count1() as u64
+}
+
+pub(crate) fn is_meta(key: &[u8]) -> bool {
+    key.starts_with(META)
+}
+
+/// Rejects the reserved keys as user keys.
+pub(crate) fn check_key(key: &[u8]) -> io::Result<()> {
+    if is_meta(key) {
+        let msg = format!("key {:?} starts with the reserved prefix \\xffmeta/", key);
+        return Err(error_invalid_input(msg));
+    }
+    Ok(())
+}
+
+/// Returns `path` as UTF-8, as RocksDB takes it.
+pub(crate) fn path_str(path: &Path) -> io::Result<&str> {
+    path.to_str()
+        .ok_or_else(|| error_invalid_input(format!("path is not valid UTF-8: {:?}", path)))
+}
+
 fn error_invalid_input(msg: String) -> io::Error {
     io::Error::new(io::ErrorKind::InvalidInput, msg)
 }
-fn error_other(msg: String) -> io::Error {
+pub(crate) fn error_other(msg: String) -> io::Error {
     io::Error::new(io::ErrorKind::Other, msg)
 }
 
@@ -23,13 +124,14 @@ impl Store {
 
     pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
         let db = {
-            let path = path.as_ref().to_str().unwrap();
+            let path = path_str(path.as_ref())?;
             let mut opts = rocksdb::DBOptions::new();
             opts.create_if_missing(true);
             rocksdb::DB::open(opts, path).map_err(error_invalid_input)?
         };
         // db.create_cf(Self::NS_INDEX).map_err(error_invalid_input)?;
-        Ok(Store { db })
+        let locks = (0..LOCKS).map(|_| Mutex::new(())).collect();
+        Ok(Store { db, locks })
     }
 
     // pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
@@ -48,26 +150,84 @@ impl Store {
     {
         let opt = self.db.get(key.as_ref()).map_err(error_other)?;
         if let Some(db_vec) = opt {
-            // from `rocksdb::DBVector` to `Vec<u8>`
-            let mut vec = db_vec.to_vec();
-            let set = bits::Set::read_from(&mut io::Cursor::new(vec))?;
+            let set = decode(&db_vec)?;
             Ok(Some(set))
         } else {
             Ok(None)
         }
     }
 
+    /// Writes the set of the given key.
+    ///
+    /// Keys starting with `\xffmeta/` are reserved for the crate's own bookkeeping:
+    /// they are skipped by `Seek` and the iterators, and may be overwritten.
     pub fn put<T>(&self, key: T, set: &bits::Set) -> io::Result<()>
     where
         T: AsRef<[u8]>,
     {
-        let vec = {
-            let mut buf = Vec::with_capacity(1024);
-            set.write_to(&mut buf)?;
-            buf
-        };
-        self.db.put(key.as_ref(), &vec[..]).map_err(error_other)?;
-        Ok(())
+        let key = key.as_ref();
+        let vec = encode(set)?;
+        let batch = rocksdb::WriteBatch::new();
+        batch.put(key, &vec[..]).map_err(error_other)?;
+        batch
+            .put(&card_key(key), &cardinality(set).to_le_bytes())
+            .map_err(error_other)?;
+        self.write(&batch)
+    }
+
+    /// Returns the number of documents in the set of the given key,
+    /// without deserializing the set if its cardinality is stored.
+    pub fn count<T>(&self, key: T) -> io::Result<Option<u64>>
+    where
+        T: AsRef<[u8]>,
+    {
+        let key = key.as_ref();
+        match self.get_bytes(&card_key(key))? {
+            Some(ref bytes) if bytes.len() == 8 => {
+                let mut word = [0; 8];
+                word.copy_from_slice(bytes);
+                Ok(Some(u64::from_le_bytes(word)))
+            }
+            _ => Ok(self.get(key)?.map(|set| cardinality(&set))),
+        }
+    }
+
+    /// Ingest external SST files, e.g. written by `Builder`.
+    /// Keys in the files overwrite the existing ones.
+    pub fn ingest<P: AsRef<Path>>(&self, files: &[P]) -> io::Result<()> {
+        let paths = files
+            .iter()
+            .map(|p| path_str(p.as_ref()))
+            .collect::<io::Result<Vec<&str>>>()?;
+        let mut opts = rocksdb::IngestExternalFileOptions::new();
+        opts.move_files(true);
+        self.db
+            .ingest_external_file(&opts, &paths)
+            .map_err(error_other)
+    }
+
+    pub(crate) fn get_bytes(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let opt = self.db.get(key).map_err(error_other)?;
+        Ok(opt.map(|db_vec| db_vec.to_vec()))
+    }
+
+    pub(crate) fn put_bytes(&self, key: &[u8], bytes: &[u8]) -> io::Result<()> {
+        self.db.put(key, bytes).map_err(error_other)
+    }
+
+    pub(crate) fn write(&self, batch: &rocksdb::WriteBatch) -> io::Result<()> {
+        self.db.write(batch).map_err(error_other)
+    }
+
+    /// Locks the reserved keys under `prefix` against other users of this store.
+    pub(crate) fn lock(&self, prefix: &[u8]) -> MutexGuard<()> {
+        let mut hasher = DefaultHasher::new();
+        prefix.hash(&mut hasher);
+        self.locks[hasher.finish() as usize % self.locks.len()].lock()
+    }
+
+    pub(crate) fn raw_iter(&self) -> rocksdb::DBIterator<&rocksdb::DB> {
+        self.db.iter()
     }
 
     pub fn seek(&self) -> Seek {
@@ -75,14 +235,32 @@ impl Store {
     }
 }
 
+/// Serialize `set` in the format `Store` keeps on disk.
+pub(crate) fn encode(set: &bits::Set) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(1024);
+    set.write_to(&mut buf)?;
+    Ok(buf)
+}
+
+/// Deserialize a set written by `encode`.
+pub(crate) fn decode(bytes: &[u8]) -> io::Result<bits::Set> {
+    bits::Set::read_from(&mut io::Cursor::new(bytes))
+}
+
 impl<'a> Seek<'a> {
+    /// Returns the first entry whose key is greater than or equal to `t`.
+    /// Reserved keys are skipped.
     pub fn next<T>(&self, t: T) -> io::Result<Option<(Vec<u8>, bits::Set)>>
     where
         T: AsRef<[u8]>,
     {
         let mut iter = self.db.iter();
         let seek_key = rocksdb::SeekKey::Key(t.as_ref());
-        if iter.seek(seek_key) {
+        let mut valid = iter.seek(seek_key);
+        if valid && is_meta(iter.key()) {
+            valid = iter.seek(rocksdb::SeekKey::Key(META_END));
+        }
+        if valid {
             let key = iter.key();
             let val = iter.value();
             let set = bits::Set::read_from(&mut io::Cursor::new(val))?;
@@ -92,13 +270,22 @@ impl<'a> Seek<'a> {
         }
     }
 
+    /// Returns the last entry whose key is less than or equal to `t`.
+    /// Reserved keys are skipped.
     pub fn prev<T>(&self, t: T) -> io::Result<Option<(Vec<u8>, bits::Set)>>
     where
         T: AsRef<[u8]>,
     {
         let mut iter = self.db.iter();
         let seek_key = rocksdb::SeekKey::Key(t.as_ref());
-        if iter.seek_for_prev(seek_key) {
+        let mut valid = iter.seek_for_prev(seek_key);
+        if valid && is_meta(iter.key()) {
+            valid = iter.seek_for_prev(rocksdb::SeekKey::Key(META));
+            if valid && is_meta(iter.key()) {
+                valid = iter.prev();
+            }
+        }
+        if valid {
             let key = iter.key();
             let val = iter.value();
             let set = bits::Set::read_from(&mut io::Cursor::new(val))?;
@@ -107,4 +294,113 @@ impl<'a> Seek<'a> {
             Ok(None)
         }
     }
+
+    /// Returns an iterator over the entries whose keys are in `[start, end)`.
+    pub fn range<T, U>(&self, start: T, end: U) -> Range<'a>
+    where
+        T: AsRef<[u8]>,
+        U: AsRef<[u8]>,
+    {
+        Range::new(self.db, start.as_ref(), Some(end.as_ref().to_vec()))
+    }
+
+    /// Returns an iterator over the entries whose keys are greater than or equal to `start`.
+    pub fn iter_from<T>(&self, start: T) -> Range<'a>
+    where
+        T: AsRef<[u8]>,
+    {
+        Range::new(self.db, start.as_ref(), None)
+    }
+
+    /// Returns an iterator over the keys greater than or equal to `start`.
+    pub fn keys_from<T>(&self, start: T) -> Keys<'a>
+    where
+        T: AsRef<[u8]>,
+    {
+        let mut iter = self.db.iter();
+        let valid = iter.seek(rocksdb::SeekKey::Key(start.as_ref()));
+        Keys { iter, valid }
+    }
+}
+
+impl<'a> Iterator for Keys<'a> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.valid {
+            if is_meta(self.iter.key()) {
+                // Skip the reserved keys at once rather than one by one.
+                self.valid = self.iter.seek(rocksdb::SeekKey::Key(META_END));
+                continue;
+            }
+            let key = self.iter.key().to_vec();
+            self.valid = self.iter.next();
+            return Some(Ok(key));
+        }
+        None
+    }
+}
+
+impl<'a> Range<'a> {
+    fn new(db: &'a rocksdb::DB, start: &[u8], end: Option<Vec<u8>>) -> Self {
+        let mut iter = db.iter();
+        let valid = iter.seek(rocksdb::SeekKey::Key(start));
+        Range { iter, end, valid }
+    }
+}
+
+impl<'a> Iterator for Range<'a> {
+    type Item = io::Result<(Vec<u8>, bits::Set)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.valid {
+            let key = self.iter.key().to_vec();
+            if let Some(ref end) = self.end {
+                if key >= *end {
+                    self.valid = false;
+                    return None;
+                }
+            }
+            if is_meta(&key) {
+                self.valid = self.iter.seek(rocksdb::SeekKey::Key(META_END));
+                continue;
+            }
+            let item = decode(self.iter.value()).map(|set| (key, set));
+            self.valid = self.iter.next();
+            return Some(item);
+        }
+        None
+    }
+}
+
+impl<T: Borrow<Store>> Backend for T {
+    fn get(&self, key: &[u8]) -> io::Result<Option<bits::Set>> {
+        self.borrow().get(key)
+    }
+
+    fn get_sized(&self, key: &[u8]) -> io::Result<Option<(bits::Set, usize)>> {
+        match self.borrow().db.get(key).map_err(error_other)? {
+            Some(db_vec) => Ok(Some((decode(&db_vec)?, db_vec.len()))),
+            None => Ok(None),
+        }
+    }
+
+    fn put(&self, key: &[u8], set: &bits::Set) -> io::Result<()> {
+        self.borrow().put(key, set)
+    }
+
+    fn iter_from<'a>(
+        &'a self,
+        start: &[u8],
+    ) -> Box<Iterator<Item = io::Result<(Bytes, bits::Set)>> + 'a> {
+        Box::new(self.borrow().seek().iter_from(start))
+    }
+
+    fn keys_from<'a>(&'a self, start: &[u8]) -> Box<Iterator<Item = io::Result<Bytes>> + 'a> {
+        Box::new(self.borrow().seek().keys_from(start))
+    }
+
+    fn count(&self, key: &[u8]) -> io::Result<Option<u64>> {
+        self.borrow().count(key)
+    }
 }
@@ -1,6 +1,10 @@
 use std::fs;
+use std::io;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 use super::*;
 
 #[test]
@@ -29,7 +33,7 @@ fn bucket_ops() {
     {
         let store = Rc::new(Store::open(path).unwrap());
         let cache = cache::Raw::new(1);
-        let mut index = Index::new(store.clone(), cache);
+        let index = Index::new(store.clone(), cache);
 
         let got_a = index.get("1").unwrap().unwrap();
         let mut got_x = index.get("1").unwrap().unwrap();
@@ -215,7 +219,1206 @@ fn store_ops() {
         assert_eq!(bitset![2], store.seek().prev("21").unwrap().unwrap().1);
         assert_eq!(bitset![3], store.seek().prev("30").unwrap().unwrap().1);
         assert_eq!(bitset![3], store.seek().prev("31").unwrap().unwrap().1);
+        assert_eq!(bitset![3], store.seek().prev(&b"\xffmeta/card/20"[..]).unwrap().unwrap().1);
+        assert_eq!(None, store.seek().next(&b"\xffmeta/"[..]).unwrap());
+    }
+
+    assert!(fs::remove_dir_all(path).is_ok());
+}
+
+#[test]
+fn builder_ops() {
+    let path = "./test_builder_ops";
+    let tmp = "./test_builder_ops_tmp";
+
+    {
+        let store = Store::open(path).unwrap();
+        let pairs = vec![
+            ("b", 3),
+            ("a", 1),
+            ("c", 9),
+            ("a", 2),
+            ("b", 3),
+            ("a", 100),
+            ("c", 1),
+        ];
+
+        let mut reports = Vec::new();
+        let done = Builder::new(tmp)
+            .memory(64) // spill every two pairs
+            .progress(|p| reports.push(*p))
+            .build(pairs, &store)
+            .unwrap();
+
+        assert_eq!(done.phase, Phase::Done);
+        assert_eq!(done.pairs, 7);
+        assert_eq!(done.runs, 4);
+        assert_eq!(done.keys, 3);
+        assert_eq!(reports.last(), Some(&done));
+
+        assert_eq!(store.get("a").unwrap().unwrap(), bitset![1, 2, 100]);
+        assert_eq!(store.get("b").unwrap().unwrap(), bitset![3]);
+        assert_eq!(store.get("c").unwrap().unwrap(), bitset![1, 9]);
+        assert_eq!(store.get("d").unwrap(), None);
+
+        let pairs = vec![(&b"d"[..], 1), (&b"\xffmeta/deleted"[..], 2)];
+        let err = Builder::new(tmp).build(pairs, &store).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(store.get("d").unwrap(), None);
+
+        // Keys above the reserved prefix go to their own file.
+        let pairs = vec![(&b"\xffz"[..], 7), (&b"e"[..], 5)];
+        Builder::new(tmp).build(pairs, &store).unwrap();
+        assert_eq!(store.get(&b"\xffz"[..]).unwrap().unwrap(), bitset![7]);
+        assert_eq!(store.count(&b"\xffz"[..]).unwrap(), Some(1));
+        let keys = store.seek().keys_from("").map(|k| k.unwrap()).collect::<Vec<_>>();
+        let want = vec![&b"a"[..], &b"b"[..], &b"c"[..], &b"e"[..], &b"\xffz"[..]];
+        assert_eq!(keys, want);
+    }
+
+    assert!(fs::remove_dir_all(path).is_ok());
+    assert!(fs::remove_dir_all(tmp).is_ok());
+}
+
+#[test]
+fn deleted_ops() {
+    let path = "./test_deleted_ops";
+
+    {
+        let store = Store::open(path).unwrap();
+        store.put("1", &bitset![1, 2, 3]).unwrap();
+        store.put("2", &bitset![2, 4]).unwrap();
+
+        let index = Index::new(&store, cache::Raw::new(1));
+        index.put("3", bitset![3, 5]).unwrap();
+        let err = index.put(&b"\xffmeta/deleted"[..], bitset![1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        assert!(index.delete(2).unwrap());
+        assert!(index.delete(3).unwrap());
+        assert!(!index.delete(3).unwrap());
+
+        assert_eq!(*index.get("1").unwrap().unwrap(), bitset![1]);
+        assert_eq!(*index.get("2").unwrap().unwrap(), bitset![4]);
+        assert_eq!(*index.get("3").unwrap().unwrap(), bitset![5]);
+        assert_eq!(
+            *index.get_including_deleted("1").unwrap().unwrap(),
+            bitset![1, 2, 3]
+        );
+        index.snapshot().unwrap();
+    }
+
+    {
+        let store = Arc::new(Store::open(path).unwrap());
+        let index = SharedIndex::new(store.clone(), cache::Raw::new(2));
+        assert_eq!(*index.deleted().unwrap().unwrap(), bitset![2, 3]);
+
+        let purged = index.purge_in_background().join().unwrap().unwrap();
+        assert_eq!(purged, bitset![2, 3]);
+        assert_eq!(index.deleted().unwrap(), None);
+
+        assert_eq!(store.get("1").unwrap().unwrap(), bitset![1]);
+        assert_eq!(store.get("2").unwrap().unwrap(), bitset![4]);
+        assert_eq!(store.get("3").unwrap().unwrap(), bitset![5]);
+        assert_eq!(
+            *index.get_including_deleted("1").unwrap().unwrap(),
+            bitset![1]
+        );
+    }
+
+    assert!(fs::remove_dir_all(path).is_ok());
+}
+
+#[test]
+fn dict_ops() {
+    let path = "./test_dict_ops";
+
+    {
+        let store = Arc::new(Store::open(path).unwrap());
+        let dict = Dictionary::new(store.clone());
+        assert_eq!(dict.assign("doc-a").unwrap(), 0);
+        assert_eq!(dict.assign("doc-b").unwrap(), 1);
+        assert_eq!(dict.assign("doc-a").unwrap(), 0);
+        assert_eq!(dict.id("doc-b").unwrap(), Some(1));
+        assert_eq!(dict.id("doc-c").unwrap(), None);
+        assert_eq!(dict.external(1).unwrap(), Some(b"doc-b".to_vec()));
+
+        assert_eq!(dict.remove("doc-a").unwrap(), Some(0));
+        assert_eq!(dict.remove("doc-a").unwrap(), None);
+        dict.recycle(&bitset![0]).unwrap(); // ignored
+        assert_eq!(dict.assign("doc-c").unwrap(), 2);
+    }
+
+    {
+        let store = Arc::new(Store::open(path).unwrap());
+        let dict = Dictionary::with_recycling(store.clone());
+        assert_eq!(dict.assign("doc-d").unwrap(), 3);
+        dict.recycle(&bitset![0]).unwrap();
+        assert_eq!(dict.assign("doc-e").unwrap(), 0);
+        assert_eq!(dict.assign("doc-f").unwrap(), 4);
+        dict.recycle(&bitset![1, 9]).unwrap(); // still mapped or never assigned
+        assert_eq!(dict.assign("doc-g").unwrap(), 5);
+
+        // Dictionaries sharing a store assign distinct ids.
+        let handles = (0..4)
+            .map(|t| {
+                let dict = Dictionary::new(store.clone());
+                thread::spawn(move || {
+                    (0..50)
+                        .map(|i| dict.assign(format!("doc-{}-{}", t, i)).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut ids = handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 200);
+
+        assert_eq!(
+            dict.externals(&bitset![0, 1, 2, 9]).unwrap(),
+            vec![b"doc-e".to_vec(), b"doc-b".to_vec(), b"doc-c".to_vec()]
+        );
+    }
+
+    assert!(fs::remove_dir_all(path).is_ok());
+}
+
+#[test]
+fn schema_ops() {
+    let path = "./test_schema_ops";
+
+    let schema = Schema::new()
+        .field("status", Kind::Keyword)
+        .field("active", Kind::Boolean)
+        .field("size", Kind::Numeric { bits: 8 })
+        .field("created", Kind::Date(Resolution::Day))
+        .field("tags", Kind::Tags);
+
+    {
+        let store = Store::open(path).unwrap();
+        schema.open(&store).unwrap();
+        schema.open(&store).unwrap();
+        let other = Schema::new().field("status", Kind::Tags);
+        assert!(other.open(&store).is_err());
+
+        let index = Index::new(&store, cache::Raw::new(8));
+        let docs = vec![
+            (1, "ok", true, 3, 86_400 * 2 + 5, vec!["a", "b"]),
+            (2, "ng", false, 7, 86_400 * 2 + 9, vec!["b"]),
+            (3, "ok", true, 7, 86_400 * 3, vec![]),
+        ];
+        for (id, status, active, size, created, tags) in docs {
+            let mut doc = Document::new();
+            doc.insert("status".into(), Value::Keyword(status.into()));
+            doc.insert("active".into(), Value::Boolean(active));
+            doc.insert("size".into(), Value::Numeric(size));
+            doc.insert("created".into(), Value::Date(created));
+            doc.insert(
+                "tags".into(),
+                Value::Tags(tags.into_iter().map(Into::into).collect()),
+            );
+            index.add_document(&schema, id, &doc).unwrap();
+        }
+
+        let find = |name, value| index.find(&schema, name, &value).unwrap();
+        assert_eq!(find("status", Value::Keyword("ok".into())), bitset![1, 3]);
+        assert_eq!(find("active", Value::Boolean(false)), bitset![2]);
+        assert_eq!(find("size", Value::Numeric(7)), bitset![2, 3]);
+        assert_eq!(find("size", Value::Numeric(3)), bitset![1]);
+        assert_eq!(find("size", Value::Numeric(1)), bits::Set::new());
+        assert_eq!(find("created", Value::Date(86_400 * 2)), bitset![1, 2]);
+        assert_eq!(find("tags", Value::Keyword("b".into())), bitset![1, 2]);
+
+        let mut doc = Document::new();
+        doc.insert("size".into(), Value::Numeric(256));
+        assert!(index.add_document(&schema, 4, &doc).is_err());
+        assert!(index.find(&schema, "color", &Value::Boolean(true)).is_err());
+    }
+
+    assert!(fs::remove_dir_all(path).is_ok());
+}
+
+#[test]
+fn partition_ops() {
+    let path = "./test_partition_ops";
+    let day = 86_400;
+
+    {
+        let mut parts = Partitioned::open(path, day, 4).unwrap();
+        assert!(parts.insert(10, "k", 1).unwrap());
+        assert!(parts.insert(day + 10, "k", 2).unwrap());
+        assert!(parts.insert(day * 2 + 10, "k", 3).unwrap());
+        assert!(!parts.insert(day * 2 + 20, "k", 3).unwrap());
+        assert_eq!(parts.partitions(), vec![0, day, day * 2]);
+
+        assert_eq!(parts.get(0..day * 3, "k").unwrap(), bitset![1, 2, 3]);
+        assert_eq!(parts.get(day + 5..day * 2, "k").unwrap(), bitset![2]);
+        assert_eq!(parts.get(5..day + 1, "k").unwrap(), bitset![1, 2]);
+        assert_eq!(parts.get(day * 3..day * 4, "k").unwrap(), bits::Set::new());
+
+        parts.seal(0).unwrap();
+        assert!(parts.is_sealed(0));
+        assert!(parts.insert(10, "k", 4).is_err());
+        parts.snapshot().unwrap();
+    }
+
+    assert!(Partitioned::open(path, day * 7, 4).is_err());
+
+    {
+        let mut parts = Partitioned::open(path, day, 4).unwrap();
+        assert!(parts.is_sealed(0));
+        assert!(!parts.is_sealed(day));
+        assert_eq!(parts.get(0..day * 3, "k").unwrap(), bitset![1, 2, 3]);
+
+        assert!(parts.drop_partition(day).unwrap());
+        assert!(!parts.drop_partition(day).unwrap());
+        assert_eq!(parts.partitions(), vec![0, day * 2]);
+        assert_eq!(parts.get(0..day * 3, "k").unwrap(), bitset![1, 3]);
+    }
+
+    assert!(fs::remove_dir_all(path).is_ok());
+}
+
+#[test]
+fn merge_ops() {
+    let paths = ["./test_merge_ops_0", "./test_merge_ops_1", "./test_merge_ops_2"];
+
+    {
+        let a = Store::open(paths[0]).unwrap();
+        a.put("x", &bitset![0, 1]).unwrap();
+        a.put("y", &bitset![2]).unwrap();
+        let b = Store::open(paths[1]).unwrap();
+        b.put("x", &bitset![0]).unwrap();
+        b.put("z", &bitset![1, 2]).unwrap();
+        Index::new(&b, cache::Raw::new(1)).delete(2).unwrap();
+
+        let out = Store::open(paths[2]).unwrap();
+        let table = [7, 8, 9];
+        merge(&[(&a, Remap::Offset(0)), (&b, Remap::Table(&table))], &out).unwrap();
+
+        assert_eq!(out.get("x").unwrap().unwrap(), bitset![0, 1, 7]);
+        assert_eq!(out.get("y").unwrap().unwrap(), bitset![2]);
+        assert_eq!(out.get("z").unwrap().unwrap(), bitset![8, 9]);
+        let index = Index::new(&out, cache::Raw::new(1));
+        assert_eq!(*index.deleted().unwrap().unwrap(), bitset![9]);
+        assert_eq!(*index.get("z").unwrap().unwrap(), bitset![8]);
+
+        let short = [0];
+        assert!(merge(&[(&b, Remap::Table(&short))], &out).is_err());
+    }
+
+    for path in &paths {
+        assert!(fs::remove_dir_all(path).is_ok());
+    }
+}
+
+#[test]
+fn file_ops() {
+    let path = "./test_file_ops";
+    let seg = "./test_file_ops.seg";
+
+    {
+        let store = Store::open(path).unwrap();
+        store.put("a", &bitset![1, 2]).unwrap();
+        store.put("c", &bitset![3]).unwrap();
+        let index = Index::new(&store, cache::Raw::new(4));
+        index.put("b", bitset![4, 5]).unwrap();
+        index.delete(5).unwrap();
+        index.write_segment(seg).unwrap();
+    }
+
+    {
+        let reader = file::Reader::open(seg).unwrap();
+        assert_eq!(reader.len(), 4); // a, b, c and the deleted documents
+        assert_eq!(reader.key(0), b"a");
+        assert_eq!(reader.get("b").unwrap().unwrap(), bitset![4, 5]);
+        assert_eq!(reader.get("bb").unwrap(), None);
+        assert_eq!(reader.next("bb").unwrap().unwrap().0, b"c".to_vec());
+        assert_eq!(reader.prev("bb").unwrap().unwrap().0, b"b".to_vec());
+        assert_eq!(reader.prev("0").unwrap(), None);
+        assert_eq!(
+            reader.range("a", "c").map(|e| e.unwrap().0).collect::<Vec<_>>(),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+        assert_eq!(reader.iter_from("").count(), 3);
+
+        let mut index = Index::new(reader, cache::Raw::new(1));
+        let reported = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&reported);
+        index.set_on_drop_error(OnDropError::report(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+        assert_eq!(*index.get("b").unwrap().unwrap(), bitset![4]);
+        assert_eq!(*index.get("a").unwrap().unwrap(), bitset![1, 2]); // clean eviction
+        assert!(index.snapshot().is_ok());
+        index.put("a", bits::Set::new()).unwrap();
+        assert!(index.snapshot().is_err());
+        drop(index);
+        assert_eq!(reported.load(Ordering::SeqCst), 1);
+    }
+
+    {
+        // A corrupt footer is an error, not a panic.
+        let mut bytes = fs::read(seg).unwrap();
+        let n = bytes.len();
+        bytes[n - 32..n - 24].copy_from_slice(&u64::max_value().to_le_bytes());
+        fs::write(seg, &bytes).unwrap();
+        let err = file::Reader::open(seg).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    {
+        let mut w = file::Writer::create(seg).unwrap();
+        w.push("b", &bitset![1]).unwrap();
+        assert!(w.push("a", &bitset![1]).is_err());
+    }
+
+    assert!(fs::remove_dir_all(path).is_ok());
+    assert!(fs::remove_file(seg).is_ok());
+}
+
+#[test]
+fn dirty_ops() {
+    let mut cache = cache::Raw::new(2);
+    assert!(cache.write(1, 10).is_empty());
+    assert!(cache.load(2, 20).is_empty());
+    assert!(cache.is_dirty(&1));
+    assert!(!cache.is_dirty(&2));
+    assert_eq!(cache.dirty_len(), 1);
+
+    let dropped = cache.load(3, 30).pop().unwrap();
+    assert_eq!((dropped.key, dropped.value, dropped.dirty), (1, 10, true));
+    let dropped = cache.write(4, 40).pop().unwrap();
+    assert_eq!((dropped.key, dropped.value, dropped.dirty), (2, 20, false));
+
+    let mut flushed = Vec::new();
+    cache
+        .flush(|k, v| -> Result<(), ()> {
+            flushed.push((*k, *v));
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(flushed, vec![(4, 40)]);
+    assert_eq!(cache.dirty_len(), 0);
+
+    cache.set_weigher(|v: &i32| *v as usize);
+    assert!(cache.write(5, 50).iter().all(|d| !d.dirty));
+    assert_eq!((cache.dirty_len(), cache.dirty_weight()), (1, 50));
+    cache.clear();
+    assert_eq!((cache.dirty_len(), cache.dirty_weight()), (0, 0));
+}
+
+#[test]
+fn facet_ops() {
+    let path = "./test_facet_ops";
+
+    {
+        let store = Store::open(path).unwrap();
+        store.put("color/blue", &bitset![1, 2, 3, 4]).unwrap();
+        store.put("color/green", &bitset![5]).unwrap();
+        store.put("color/red", &bitset![1, 2, 5, 6]).unwrap();
+        store.put("size/l", &bitset![1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(store.count("color/red").unwrap(), Some(4));
+        assert_eq!(store.count("color/none").unwrap(), None);
+
+        let index = Index::new(&store, cache::Raw::new(4));
+        index.put("color/white", bitset![2, 3, 4, 6]).unwrap(); // cached only
+        index.delete(4).unwrap();
+
+        let filter = bitset![1, 2, 3, 4, 5];
+        assert_eq!(
+            index.facet_counts("color/", &filter, 2).unwrap(),
+            vec![(b"color/blue".to_vec(), 3), (b"color/red".to_vec(), 3)]
+        );
+        assert_eq!(
+            index.facet_counts("color/", &filter, 10).unwrap(),
+            vec![
+                (b"color/blue".to_vec(), 3),
+                (b"color/red".to_vec(), 3),
+                (b"color/white".to_vec(), 2),
+                (b"color/green".to_vec(), 1),
+            ]
+        );
+        assert!(index.facet_counts("color/", &filter, 0).unwrap().is_empty());
+        assert!(index.facet_counts("shape/", &filter, 3).unwrap().is_empty());
+    }
+
+    assert!(fs::remove_dir_all(path).is_ok());
+}
+
+#[test]
+fn page_ops() {
+    let set = bitset![1, 3, 5, 7, 9, 11, 13];
+
+    assert_eq!(Page::new(&set).to_vec(), vec![1, 3, 5, 7, 9, 11, 13]);
+    assert_eq!(Page::new(&set).skip(2).limit(3).to_vec(), vec![5, 7, 9]);
+    assert_eq!(Page::new(&set).after(5).limit(2).to_vec(), vec![7, 9]);
+    assert_eq!(Page::new(&set).after(6).limit(2).to_vec(), vec![7, 9]);
+    assert_eq!(Page::new(&set).after(13).to_vec(), Vec::<u32>::new());
+    assert_eq!(Page::new(&set).skip(10).to_vec(), Vec::<u32>::new());
+
+    assert_eq!(Page::new(&set).rev().limit(3).to_vec(), vec![13, 11, 9]);
+    assert_eq!(Page::new(&set).rev().after(9).limit(2).to_vec(), vec![7, 5]);
+    assert_eq!(Page::new(&set).rev().after(8).skip(1).to_vec(), vec![5, 3, 1]);
+    assert_eq!(Page::new(&set).rev().after(1).to_vec(), Vec::<u32>::new());
+
+    let page = Page::new(&set).skip(1).limit(4);
+    assert_eq!(page.iter().len(), 4);
+    assert_eq!(page.iter().rev().collect::<Vec<_>>(), vec![9, 7, 5, 3]);
+
+    // Walk pages with a cursor.
+    let mut pages = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = match cursor {
+            Some(id) => Page::new(&set).after(id),
+            None => Page::new(&set),
+        };
+        let ids = page.limit(3).to_vec();
+        if ids.is_empty() {
+            break;
+        }
+        cursor = ids.last().cloned();
+        pages.push(ids);
+    }
+    assert_eq!(pages, vec![vec![1, 3, 5], vec![7, 9, 11], vec![13]]);
+}
+
+#[test]
+fn close_ops() {
+    let path = "./test_close_ops";
+
+    {
+        let store = Store::open(path).unwrap();
+        let index = Index::new(&store, cache::Raw::new(4));
+        index.put("1", bitset![1]).unwrap();
+        index.close().unwrap();
+        assert_eq!(store.get("1").unwrap().unwrap(), bitset![1]);
+
+        {
+            let index = Index::new(&store, cache::Raw::new(4));
+            index.put("2", bitset![2]).unwrap();
+        }
+        assert_eq!(store.get("2").unwrap().unwrap(), bitset![2]);
+    }
+
+    {
+        let store = Arc::new(Store::open(path).unwrap());
+        let index = SharedIndex::new(store.clone(), cache::Raw::new(4));
+        let other = index.clone();
+        index.put("3", bitset![3]).unwrap();
+        other.put("4", bitset![4]).unwrap();
+
+        drop(index);
+        assert_eq!(store.get("3").unwrap(), None); // `other` is still alive
+        drop(other);
+        assert_eq!(store.get("3").unwrap().unwrap(), bitset![3]);
+        assert_eq!(store.get("4").unwrap().unwrap(), bitset![4]);
+    }
+
+    assert!(fs::remove_dir_all(path).is_ok());
+}
+
+#[test]
+#[should_panic(expected = "failed to flush index on drop")]
+fn close_panic_ops() {
+    let path = "./test_close_panic_ops";
+    let seg = "./test_close_panic_ops.seg";
+    {
+        let store = Store::open(path).unwrap();
+        file::Writer::write_store(&store, seg).unwrap();
+    }
+    let _ = fs::remove_dir_all(path);
+
+    let reader = file::Reader::open(seg).unwrap();
+    let _ = fs::remove_file(seg);
+    let mut index = Index::new(reader, cache::Raw::new(4));
+    index.set_on_drop_error(OnDropError::Panic);
+    index.put("1", bitset![1]).unwrap();
+}
+
+#[test]
+fn flusher_ops() {
+    let path = "./test_flusher_ops";
+
+    {
+        let store = Arc::new(Store::open(path).unwrap());
+        let index = SharedIndex::new(store.clone(), cache::Raw::new(4));
+        let policy = FlushPolicy {
+            interval: Duration::from_secs(3600),
+            max_dirty: Some(1),
+            poll: Duration::from_millis(10),
+            ..FlushPolicy::default()
+        };
+        let flusher = index.start_flusher(policy, |err| panic!("{}", err));
+
+        index.put("1", bitset![1]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(store.get("1").unwrap(), None); // below the threshold
+
+        index.put("2", bitset![2]).unwrap();
+        for _ in 0..100 {
+            if store.get("2").unwrap().is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(store.get("1").unwrap().unwrap(), bitset![1]);
+        assert_eq!(store.get("2").unwrap().unwrap(), bitset![2]);
+
+        index.put("3", bitset![3]).unwrap();
+        flusher.stop().unwrap(); // flushes once more
+        assert_eq!(store.get("3").unwrap().unwrap(), bitset![3]);
+    }
+
+    assert!(fs::remove_dir_all(path).is_ok());
+}
+
+#[test]
+fn concurrent_ops() {
+    fn is_send_sync<T: Send + Sync>(_: &T) {}
+
+    let path = "./test_concurrent_ops";
+
+    {
+        let store = Arc::new(Store::open(path).unwrap());
+        let index = SharedIndex::new(store.clone(), cache::Raw::new(2));
+        is_send_sync(&index);
+
+        let workers = (0..4u32)
+            .map(|n| {
+                let index = index.clone();
+                thread::spawn(move || {
+                    for id in 0..100 {
+                        let key = format!("{}", id % 5);
+                        assert!(index.insert(&key, n * 100 + id).unwrap());
+                        if id % 10 == 0 {
+                            index.snapshot().unwrap();
+                        }
+                    }
+                    assert!(index.remove("0", n * 100).unwrap());
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        index.snapshot().unwrap();
+        for k in 0..5u32 {
+            let want = (0..4u32)
+                .flat_map(|n| (0..100).filter(move |id| id % 5 == k).map(move |id| n * 100 + id))
+                .filter(|&id| id % 100 != 0)
+                .collect::<bits::Set>();
+            let key = format!("{}", k);
+            assert_eq!(*index.get(&key).unwrap().unwrap(), want);
+            assert_eq!(store.get(&key).unwrap().unwrap(), want);
+        }
+    }
+
+    assert!(fs::remove_dir_all(path).is_ok());
+}
+
+#[test]
+fn shard_ops() {
+    use cache::Cache;
+
+    let path = "./test_shard_ops";
+
+    {
+        let store = Arc::new(Store::open(path).unwrap());
+        let cache = cache::Shared::with_shards(4, 10);
+        assert_eq!(cache.shards(), 4);
+        assert_eq!(cache.capacity(), 10);
+        cache.set_capacity(7);
+        assert_eq!(cache.capacity(), 7);
+        cache.set_capacity(2);
+        assert_eq!(cache.capacity(), 4);
+        cache.set_capacity(10);
+        let index = SharedIndex::with_cache(store.clone(), cache);
+        for i in 0..100 {
+            index.insert(format!("{}", i % 20), i).unwrap();
+        }
+        for k in 0..20 {
+            let want = (0..5).map(|n| n * 20 + k).collect::<bits::Set>();
+            assert_eq!(*index.get(format!("{}", k)).unwrap().unwrap(), want);
+        }
+        index.snapshot().unwrap();
+        assert_eq!(store.get("7").unwrap().unwrap(), bitset![7, 27, 47, 67, 87]);
+    }
+
+    {
+        let store = Arc::new(Store::open(path).unwrap());
+        let cache = cache::Shared::sharded(vec![cache::Raw::new(0)]);
+        let index = SharedIndex::with_cache(store, cache);
+        assert_eq!(index.cache_capacity(), 1);
+        assert_eq!(*index.get("7").unwrap().unwrap(), bitset![7, 27, 47, 67, 87]);
     }
 
     assert!(fs::remove_dir_all(path).is_ok());
 }
+
+#[test]
+fn policy_ops() {
+    {
+        let mut cache = cache::Raw::with_policy(2, cache::Policy::Lfu);
+        cache.put(1, 10);
+        cache.put(2, 20);
+        cache.get(&1);
+        cache.get(&1);
+        assert_eq!(cache.put(3, 30), Some((2, 20)));
+        assert_eq!(cache.put(4, 40), Some((3, 30)));
+        assert_eq!(cache.lru(), Some((&1, &10)));
+
+        // Entries hit as often go in least recently used order.
+        let mut cache = cache::Raw::with_policy(3, cache::Policy::Lfu);
+        for k in 1..4 {
+            cache.put(k, k * 10);
+        }
+        for k in &[2, 1, 3, 3] {
+            cache.get(k);
+        }
+        assert!(cache.pin(&2));
+        let dropped = cache.set_capacity(2);
+        assert_eq!(dropped.iter().map(|d| d.key).collect::<Vec<_>>(), vec![1]);
+        assert!(cache.unpin(&2));
+        let dropped = cache.set_capacity(1);
+        assert_eq!(dropped.iter().map(|d| d.key).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(cache.lru(), Some((&3, &30)));
+    }
+    {
+        let mut cache = cache::Raw::with_policy(2, cache::Policy::Clock);
+        cache.put(1, 10);
+        cache.put(2, 20);
+        cache.get(&1);
+        assert_eq!(cache.put(3, 30), Some((2, 20)));
+        assert_eq!(cache.put(4, 40), Some((3, 30)));
+    }
+    {
+        let mut cache = cache::Raw::with_policy(4, cache::Policy::Slru { protected: 50 });
+        cache.put(1, 10);
+        cache.put(2, 20);
+        cache.get(&1);
+        cache.get(&2);
+        for k in 3..10 {
+            cache.put(k, k * 10);
+        }
+        assert!(cache.exists(&1) && cache.exists(&2));
+        assert_eq!(cache.len(), 4);
+    }
+    {
+        let mut cache = cache::Raw::with_policy(4, cache::Policy::TinyLfu { window: 25 });
+        for k in 1..4 {
+            cache.put(k, k * 10);
+        }
+        for _ in 0..3 {
+            for k in 1..4 {
+                cache.get(&k);
+            }
+        }
+        for k in 100..110 {
+            cache.put(k, k * 10);
+        }
+        assert!(cache.exists(&1) && cache.exists(&2) && cache.exists(&3));
+        assert_eq!(cache.len(), 4);
+
+        cache.set_capacity(2);
+        assert_eq!(cache.len(), 2);
+    }
+    for &policy in &[cache::Policy::Lru, cache::Policy::Lfu] {
+        // Entries hit under unsegmented policies are never protected.
+        let mut cache = cache::Raw::with_policy(1, policy);
+        cache.put(1, 10);
+        cache.get(&1);
+        cache.put(1, 11);
+        cache.get(&1);
+        assert!(cache.pin(&1));
+        assert_eq!(cache.remove(&1), Some(11));
+        assert_eq!(cache.put(2, 20), None);
+        assert_eq!(cache.lru(), Some((&2, &20)));
+    }
+}
+
+#[test]
+fn weight_ops() {
+    {
+        let mut cache = cache::Raw::new(usize::max_value());
+        cache.set_weigher(|v: &Vec<u8>| v.len());
+        cache.set_max_weight(10);
+        assert!(cache.write(1, vec![0; 4]).is_empty());
+        assert!(cache.write(2, vec![0; 4]).is_empty());
+        assert_eq!(cache.weight(), 8);
+
+        // Evicts until the weight fits.
+        let dropped = cache.write(3, vec![0; 8]);
+        assert_eq!(dropped.iter().map(|d| d.key).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(cache.weight(), 8);
+
+        // A put returns only the first entry dropped.
+        cache.clear();
+        cache.put(1, vec![0; 4]);
+        cache.put(2, vec![0; 4]);
+        assert_eq!(cache.put(3, vec![0; 8]), Some((1, vec![0; 4])));
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.weight(), 8);
+
+        // Too heavy to be cached.
+        let dropped = cache.write(3, vec![0; 11]);
+        assert_eq!((dropped.len(), dropped[0].key, dropped[0].dirty), (1, 3, true));
+        assert!(cache.is_empty());
+        assert_eq!(cache.weight(), 0);
+    }
+
+    for &policy in &[
+        cache::Policy::Lru,
+        cache::Policy::Lfu,
+        cache::Policy::Slru { protected: 80 },
+        cache::Policy::Clock,
+        cache::Policy::TinyLfu { window: 1 },
+    ] {
+        // Limited by weight only, whatever the policy.
+        let mut cache = cache::Raw::with_policy(usize::max_value(), policy);
+        cache.set_weigher(|v: &Vec<u8>| v.len());
+        cache.set_max_weight(10);
+        for k in 0..10 {
+            cache.write(k, vec![0; 4]);
+            cache.get(&k);
+        }
+        assert_eq!((cache.len(), cache.weight()), (2, 8));
+    }
+
+    let path = "./test_weight_ops";
+    {
+        let store = Store::open(path).unwrap();
+        let mut raw = cache::Raw::new(usize::max_value());
+        raw.set_max_weight(bitset![1].mem_size() * 2);
+        let index = Index::new(&store, raw);
+        index.put("small", bitset![1]).unwrap();
+        let huge = (0..1 << 20).collect::<bits::Set>();
+        index.put("huge", huge.clone()).unwrap();
+        assert_eq!(store.get("huge").unwrap().unwrap(), huge);
+        assert_eq!(*index.get("huge").unwrap().unwrap(), huge);
+        assert_eq!(store.get("small").unwrap(), None);
+    }
+    assert!(fs::remove_dir_all(path).is_ok());
+}
+
+#[test]
+fn expiry_ops() {
+    let clock = cache::ManualClock::new();
+    {
+        let mut cache = cache::Raw::new(10);
+        cache.set_clock(clock.clone());
+        cache.set_expire_after_write(Some(Duration::from_secs(10)));
+        cache.write(1, 10);
+        cache.load(2, 20);
+        cache.load_with_ttl(3, 30, Duration::from_secs(1));
+
+        clock.advance(Duration::from_secs(2));
+        assert!(cache.get(&3).is_none());
+        assert!(cache.get(&2).is_some());
+
+        clock.advance(Duration::from_secs(9));
+        assert!(cache.get(&2).is_none());
+        // Modified entries wait to be written back.
+        assert_eq!(cache.get(&1), Some(&mut 10));
+        let expired = cache.purge_expired();
+        assert_eq!((expired.len(), expired[0].key, expired[0].dirty), (1, 1, true));
+        assert!(cache.is_empty());
+    }
+    {
+        let mut cache = cache::Raw::new(10);
+        cache.set_clock(clock.clone());
+        cache.set_expire_after_access(Some(Duration::from_secs(5)));
+        cache.load(1, 10);
+        clock.advance(Duration::from_secs(3));
+        assert!(cache.get(&1).is_some());
+        clock.advance(Duration::from_secs(3));
+        assert!(cache.get(&1).is_some());
+        clock.advance(Duration::from_secs(6));
+        assert!(cache.get(&1).is_none());
+    }
+    {
+        // Expired entries at the least recently used end go before evicting any.
+        let mut cache = cache::Raw::new(2);
+        cache.set_clock(clock.clone());
+        cache.write_with_ttl(1, 10, Duration::from_secs(1));
+        cache.load(2, 20);
+        clock.advance(Duration::from_secs(2));
+        let dropped = cache.load(3, 30);
+        let causes = dropped.iter().map(|d| (d.key, d.cause)).collect::<Vec<_>>();
+        assert_eq!(causes, vec![(1, cache::Cause::Expired)]);
+        assert!(dropped[0].dirty);
+        assert_eq!(cache.len(), 2);
+    }
+
+    let path = "./test_expiry_ops";
+    {
+        let store = Store::open(path).unwrap();
+        let mut raw = cache::Raw::new(10);
+        raw.set_clock(clock.clone());
+        raw.set_expire_after_write(Some(Duration::from_secs(1)));
+        let index = Index::new(&store, raw);
+        index.put("1", bitset![1]).unwrap();
+        clock.advance(Duration::from_secs(2));
+        index.purge_expired().unwrap();
+        assert_eq!(store.get("1").unwrap().unwrap(), bitset![1]);
+    }
+    assert!(fs::remove_dir_all(path).is_ok());
+}
+
+#[test]
+fn stats_ops() {
+    {
+        let mut cache = cache::Raw::new(2);
+        cache.load(1, 10);
+        cache.write(2, 20);
+        cache.load(3, 30);
+        assert!(cache.get(&1).is_none());
+        assert!(cache.get(&3).is_some());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!((stats.inserts, stats.evictions, stats.write_backs), (3, 1, 0));
+        assert_eq!(stats.hit_ratio(), Some(0.5));
+
+        cache.load(4, 40);
+        assert_eq!(cache.stats().write_backs, 1);
+        cache.reset_stats();
+        assert_eq!(cache.stats().hits, 0);
+    }
+
+    let path = "./test_stats_ops";
+    {
+        let store = Store::open(path).unwrap();
+        store.put("1", &bitset![1, 2]).unwrap();
+        let index = Index::new(&store, cache::Raw::new(10));
+        index.get_including_deleted("1").unwrap();
+        index.get_including_deleted("1").unwrap();
+        index.get_including_deleted("2").unwrap();
+
+        let stats = index.cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.inserts), (1, 2, 1));
+        let stats = index.store_stats();
+        assert_eq!(stats.reads, 2);
+        let len = store::encode(&bitset![1, 2]).unwrap().len();
+        assert_eq!(stats.bytes_decoded, len as u64);
+        assert_eq!(stats.write_back_failures, 0);
+
+        index.reset_stats();
+        assert_eq!(index.store_stats(), StoreStats::default());
+    }
+    assert!(fs::remove_dir_all(path).is_ok());
+}
+
+#[test]
+fn listener_ops() {
+    use std::sync::Mutex;
+    use cache::Cause;
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut cache = cache::Raw::new(2);
+    {
+        let events = Arc::clone(&events);
+        cache.set_listener(move |out: &cache::Dropped<i32, i32>| {
+            events.lock().unwrap().push((out.key, out.value, out.dirty, out.cause));
+        });
+    }
+    cache.write(1, 10);
+    cache.load(2, 20);
+    cache.load(2, 21);
+    cache.load(3, 30);
+    cache.remove(&2);
+    cache.write(4, 40);
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            (2, 20, false, Cause::Replaced),
+            (1, 10, true, Cause::Capacity),
+            (2, 21, false, Cause::Removed),
+        ]
+    );
+
+    let dropped = cache.set_capacity(1);
+    assert_eq!((dropped.len(), dropped[0].key, dropped[0].cause), (1, 3, Cause::Capacity));
+    let dropped = cache.clear();
+    assert_eq!((dropped[0].key, dropped[0].dirty, dropped[0].cause), (4, true, Cause::Cleared));
+    assert_eq!(events.lock().unwrap().len(), 5);
+    assert_eq!(cache.stats().evictions, 2);
+}
+
+#[test]
+fn absent_ops() {
+    let path = "./test_absent_ops";
+    {
+        let store = Store::open(path).unwrap();
+        let index = Index::new(&store, cache::Raw::new(10));
+        assert!(index.get("typo").unwrap().is_none());
+        assert!(index.get("typo").unwrap().is_none());
+        assert_eq!(index.store_stats().reads, 1);
+
+        index.put("typo", bitset![1]).unwrap();
+        assert_eq!(*index.get("typo").unwrap().unwrap(), bitset![1]);
+
+        index.set_absent_capacity(0);
+        let reads = index.store_stats().reads;
+        assert!(index.get("other").unwrap().is_none());
+        assert!(index.get("other").unwrap().is_none());
+        assert_eq!(index.store_stats().reads, reads + 2);
+    }
+    {
+        let store = Arc::new(Store::open(path).unwrap());
+        let index = SharedIndex::new(store, cache::Raw::new(1));
+        assert!(index.get("gone").unwrap().is_none());
+        index.clone().insert("gone", 7).unwrap();
+        // Evict the set to the store, then look it up again.
+        index.put("other", bitset![1]).unwrap();
+        assert!(index.get("gone").unwrap().unwrap().get(7));
+    }
+    assert!(fs::remove_dir_all(path).is_ok());
+}
+
+#[test]
+fn single_flight_ops() {
+    use std::sync::Barrier;
+
+    // A store slow enough that concurrent misses overlap.
+    #[derive(Clone)]
+    struct Slow(Arc<Store>, Arc<AtomicUsize>);
+    impl Backend for Slow {
+        fn get(&self, key: &[u8]) -> io::Result<Option<bits::Set>> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            self.0.get(key)
+        }
+        fn put(&self, key: &[u8], set: &bits::Set) -> io::Result<()> {
+            self.0.put(key, set)
+        }
+        fn iter_from<'a>(
+            &'a self,
+            start: &[u8],
+        ) -> Box<Iterator<Item = io::Result<(Bytes, bits::Set)>> + 'a> {
+            self.0.iter_from(start)
+        }
+        fn keys_from<'a>(&'a self, start: &[u8]) -> Box<Iterator<Item = io::Result<Bytes>> + 'a> {
+            self.0.keys_from(start)
+        }
+    }
+
+    let path = "./test_single_flight_ops";
+    {
+        let store = Arc::new(Store::open(path).unwrap());
+        store.put("hot", &bitset![1, 2, 3]).unwrap();
+        let reads = Arc::new(AtomicUsize::new(0));
+        let index = SharedIndex::new(Slow(store, Arc::clone(&reads)), cache::Raw::new(10));
+
+        let barrier = Arc::new(Barrier::new(8));
+        let handles = (0..8)
+            .map(|_| {
+                let (index, barrier) = (index.clone(), Arc::clone(&barrier));
+                thread::spawn(move || {
+                    barrier.wait();
+                    index.get_including_deleted("hot").unwrap().unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        let sets = handles.into_iter().map(|h| h.join().unwrap()).collect::<Vec<_>>();
+        assert!(sets.iter().all(|set| Arc::ptr_eq(set, &sets[0])));
+        assert_eq!(reads.load(Ordering::SeqCst), 1);
+    }
+    assert!(fs::remove_dir_all(path).is_ok());
+}
+
+#[test]
+fn pin_ops() {
+    for &policy in &[
+        cache::Policy::Lru,
+        cache::Policy::Lfu,
+        cache::Policy::Slru { protected: 50 },
+        cache::Policy::Clock,
+        cache::Policy::TinyLfu { window: 1 },
+    ] {
+        let mut cache = cache::Raw::with_policy(2, policy);
+        cache.load(1, 10);
+        assert!(cache.pin(&1));
+        assert!(!cache.pin(&9));
+        cache.load(2, 20);
+        cache.load(3, 30);
+        assert!(cache.exists(&1));
+        assert_eq!((cache.len(), cache.pinned_len()), (2, 1));
+
+        assert!(cache.pin(&3));
+        // Full of pinned entries, so new ones are dropped right away.
+        let dropped = cache.write(4, 40);
+        assert_eq!((dropped[0].key, dropped[0].dirty), (4, true));
+        assert_eq!(cache.len(), 2);
+
+        assert!(cache.unpin(&1));
+        assert!(!cache.unpin(&1));
+        cache.load(5, 50);
+        assert!(!cache.exists(&1));
+    }
+
+    let path = "./test_pin_ops";
+    {
+        let store = Store::open(path).unwrap();
+        store.put("universe", &bitset![1, 2, 3]).unwrap();
+        let index = Index::new(&store, cache::Raw::new(1));
+        assert!(index.pin("universe").unwrap());
+        assert!(!index.pin("nothing").unwrap());
+        index.put("1", bitset![1]).unwrap();
+        assert_eq!(store.get("1").unwrap().unwrap(), bitset![1]);
+        assert!(index.unpin("universe"));
+    }
+    assert!(fs::remove_dir_all(path).is_ok());
+}
+
+#[test]
+fn warm_up_ops() {
+    let path = "./test_warm_up_ops";
+    let hot = "./test_warm_up_ops.keys";
+    {
+        let store = Arc::new(Store::open(path).unwrap());
+        for key in &["1", "2", "3"] {
+            store.put(key, &bitset![1]).unwrap();
+        }
+        {
+            let index = Index::new(&*store, cache::Raw::new(10));
+            index.get("3").unwrap();
+            index.get("1").unwrap();
+            index.save_hot_keys(hot).unwrap();
+        }
+        {
+            let index = Index::new(&*store, cache::Raw::new(1));
+            assert_eq!(index.warm_up_from(hot, None).unwrap(), 2);
+            // The most recently used set is kept.
+            index.reset_stats();
+            index.get_including_deleted("1").unwrap();
+            assert_eq!(index.cache_stats().hits, 1);
+            assert_eq!(index.warm_up(vec!["2", "4"], Some(Duration::from_secs(0))).unwrap(), 0);
+        }
+        {
+            let index = SharedIndex::new(store, cache::Raw::new(10));
+            assert_eq!(index.warm_up_in_background(hot, None).join().unwrap().unwrap(), 2);
+            assert_eq!(index.cache_stats().inserts, 2);
+        }
+    }
+    assert!(fs::remove_dir_all(path).is_ok());
+    assert!(fs::remove_file(hot).is_ok());
+}
+
+#[test]
+fn entry_ops() {
+    use cache::{Cache, Entry};
+
+    let mut cache = cache::Raw::new(2);
+    match cache.entry(1) {
+        Entry::Vacant(e) => assert!(e.load(10).is_empty()),
+        Entry::Occupied(_) => unreachable!(),
+    }
+    match cache.entry(1) {
+        Entry::Occupied(mut e) => {
+            *e.get_mut() += 1;
+            assert!(!e.is_dirty());
+        }
+        Entry::Vacant(_) => unreachable!(),
+    }
+    assert_eq!(cache.entry(2).or_load_with(|| 20).0, Some(&mut 20));
+    assert_eq!(cache.entry(2).or_load_with(|| 21).0, Some(&mut 20));
+    assert_eq!(cache.stats().hits, 2);
+
+    let single = cache::Single::new(cache::Raw::new(2));
+    let (v, _) = single.get_or_insert_with(&1, || Rc::new(10));
+    assert_eq!(*v, 10);
+    let (v, _) = single.get_or_insert_with(&1, || Rc::new(11));
+    assert_eq!(*v, 10);
+    let r: Result<_, ()> = single.get_or_try_insert_with(&2, || Ok(None));
+    assert_eq!(r.unwrap().0, None);
+    assert!(single.get_or_try_insert_with(&2, || Err(())).is_err());
+
+    let shared = cache::Shared::with_shards(4, 8);
+    let (a, _) = shared.get_or_insert_with(&"key".to_owned(), || Arc::new(1));
+    let (b, _) = shared.get_or_insert_with(&"key".to_owned(), || Arc::new(2));
+    assert!(Arc::ptr_eq(&a, &b));
+}
+
+#[test]
+fn manage_ops() {
+    use cache::Cache;
+
+    let shared = cache::Shared::with_shards(2, 8);
+    for i in 0..4 {
+        shared.load(i, Arc::new(i));
+    }
+    assert_eq!((shared.len(), shared.capacity()), (4, 8));
+    let out = shared.remove(&0).unwrap();
+    assert_eq!((out.dirty, out.cause), (false, cache::Cause::Removed));
+    assert!(!shared.exists(&0));
+    assert_eq!(shared.entries().len(), 3);
+    assert!(shared.lru().is_some());
+    assert_eq!(shared.clear().len(), 3);
+    assert!(shared.is_empty());
+
+    let path = "./test_manage_ops";
+    {
+        let store = Store::open(path).unwrap();
+        let index = Index::new(&store, cache::Raw::new(4));
+        for key in &["1", "2", "3"] {
+            index.put(key, bitset![1]).unwrap();
+        }
+        assert_eq!(index.cached_keys(), vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()]);
+        assert_eq!(index.cache_lru(), Some(b"1".to_vec()));
+
+        index.set_cache_capacity(2).unwrap();
+        assert_eq!((index.cache_len(), index.cache_capacity()), (2, 2));
+        assert!(store.get("1").unwrap().is_some());
+
+        assert!(index.evict("2").unwrap());
+        assert!(!index.evict("2").unwrap());
+        assert!(store.get("2").unwrap().is_some());
+
+        index.clear_cache().unwrap();
+        assert!(!index.is_cached("3"));
+        assert!(store.get("3").unwrap().is_some());
+    }
+    assert!(fs::remove_dir_all(path).is_ok());
+}
+
+#[test]
+fn tier_ops() {
+    let path = "./test_tier_ops";
+    {
+        let store = Store::open(path).unwrap();
+        let index = Index::new(&store, cache::Raw::new(1));
+        index.set_serialized_tier(cache::Raw::new(100));
+        index.put("1", bitset![1]).unwrap();
+        index.put("2", bitset![2]).unwrap();
+
+        // "1" was evicted, written and serialized.
+        index.reset_stats();
+        assert_eq!(*index.get_including_deleted("1").unwrap().unwrap(), bitset![1]);
+        assert_eq!(index.store_stats().reads, 0);
+        let stats = index.serialized_stats();
+        assert_eq!((stats.hits, stats.inserts), (1, 1));
+
+        // A put replaces the serialized set.
+        index.put("2", bitset![3]).unwrap();
+        assert_eq!(*index.get_including_deleted("2").unwrap().unwrap(), bitset![3]);
+    }
+    {
+        let store = Arc::new(Store::open(path).unwrap());
+        let index = SharedIndex::new(store, cache::Raw::new(1));
+        index.set_serialized_tier(cache::Raw::new(100));
+        index.get_including_deleted("1").unwrap();
+        index.get_including_deleted("2").unwrap();
+        index.reset_stats();
+        assert_eq!(*index.get_including_deleted("1").unwrap().unwrap(), bitset![1]);
+        assert_eq!(index.store_stats().reads, 0);
+        assert_eq!(index.serialized_stats().hits, 1);
+    }
+    assert!(fs::remove_dir_all(path).is_ok());
+}